@@ -36,14 +36,17 @@ fn main() {
         .write_all(new_contents.as_bytes())
         .unwrap();
 
-    let mut f = File::create(proto_path.join("services.rs")).unwrap();
-    if let Some(path) = env::var("KRPC_SERVICES")
+    let service_definitions = env::var("KRPC_SERVICES")
         .ok()
         .map(|path| Path::new(&path).to_owned())
         .filter(|path| path.exists())
-    {
-        krpc_build::build(path.to_str().unwrap(), &mut f).unwrap();
-    } else {
-        krpc_build::build("service_definitions/", &mut f).unwrap();
+        .unwrap_or_else(|| Path::new("service_definitions/").to_owned());
+
+    let mut f = File::create(proto_path.join("services.rs")).unwrap();
+    krpc_build::build(&service_definitions, &mut f).unwrap();
+
+    if env::var("CARGO_FEATURE_TOKIO").is_ok() {
+        let mut f = File::create(proto_path.join("services_async.rs")).unwrap();
+        krpc_build::build_async(&service_definitions, &mut f).unwrap();
     }
 }
@@ -15,7 +15,7 @@ fn main() -> Result<(), Box<dyn Error>> {
 
     // Wait for updates, and print the current value.
     for _ in 0..10 {
-        ut_stream.wait();
+        ut_stream.wait()?;
         println!("It's {} o'clock", ut_stream.get()?);
     }
 
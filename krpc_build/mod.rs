@@ -1,4 +1,4 @@
-use std::{env, ffi::OsStr, fs, io, path::Path};
+use std::{ffi::OsStr, fs, io, path::Path};
 
 use convert_case::{Case, Casing};
 use proc_macro2::TokenStream;
@@ -51,6 +51,28 @@ type TokenSet<'a> = Box<dyn Iterator<Item = TokenStream> + 'a>;
 pub fn build<O: io::Write>(
     service_definitions: impl AsRef<Path>,
     out: &mut O,
+) -> Result<(), io::Error> {
+    build_services(service_definitions, false, out)
+}
+
+/// Generate source code from JSON service definitions, with
+/// async procedures.
+///
+/// The same as [`build`], except that procedures return
+/// futures, and enumerations and exceptions are re-exported
+/// from the blocking module of the same name in
+/// `crate::services`.
+pub fn build_async<O: io::Write>(
+    service_definitions: impl AsRef<Path>,
+    out: &mut O,
+) -> Result<(), io::Error> {
+    build_services(service_definitions, true, out)
+}
+
+fn build_services<O: io::Write>(
+    service_definitions: impl AsRef<Path>,
+    asynchronous: bool,
+    out: &mut O,
 ) -> Result<(), io::Error> {
     for service_definition_path in fs::read_dir(service_definitions)? {
         let path = service_definition_path.unwrap().path();
@@ -64,8 +86,11 @@ pub fn build<O: io::Write>(
         for (service_name, service_definition) in
             service_definition_json.as_object().unwrap().into_iter()
         {
-            let service_module =
-                generate_module_definition(service_name, service_definition);
+            let service_module = generate_module_definition(
+                service_name,
+                service_definition,
+                asynchronous,
+            );
 
             #[cfg(feature = "fmt")]
             let service_module =
@@ -80,19 +105,45 @@ pub fn build<O: io::Write>(
 fn generate_module_definition(
     service_name: &str,
     service_definition: &Value,
+    asynchronous: bool,
 ) -> TokenStream {
     let service_mod_name =
         format_ident!("{}", service_name.to_case(Case::Snake));
     let q_service_name = format_ident!("{}", service_name);
 
     let classes = generate_class_definitions(service_definition);
-    let enums = generate_enum_definitions(service_definition);
     let procedures = generate_procedure_definitions(
         service_definition,
         service_name,
         &q_service_name,
+        asynchronous,
     );
 
+    // Enumerations and exceptions don't call procedures, so
+    // both flavors of a service share them.
+    let enums_and_exceptions = if asynchronous {
+        let names = service_definition
+            .get("enumerations")
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .map(|name| format_ident!("{name}"));
+        quote! {
+            pub use crate::services::#service_mod_name::{
+                #(#names,)* Exception
+            };
+        }
+    } else {
+        let enums = generate_enum_definitions(service_definition);
+        let exceptions =
+            generate_exception_definitions(service_definition, service_name);
+        quote! {
+            #(#enums)*
+            #exceptions
+        }
+    };
+
     let arc_client = quote! {
         ::std::sync::Arc<crate::Client>
     };
@@ -108,16 +159,31 @@ fn generate_module_definition(
             #[derive(Clone)]
             pub struct #q_service_name {
                 pub client: #arc_client,
+                timeout: Option<::std::time::Duration>,
             }
 
             impl #q_service_name {
                 pub fn new(client: #arc_client) -> Self {
-                    Self { client }
+                    Self { client, timeout: None }
+                }
+
+                /// A handle to the service whose procedure calls
+                /// fail with [`RpcError::Timeout`] after
+                /// `timeout`, instead of the client's call
+                /// timeout.
+                pub fn with_timeout(
+                    &self,
+                    timeout: ::std::time::Duration,
+                ) -> Self {
+                    Self {
+                        client: self.client.clone(),
+                        timeout: Some(timeout),
+                    }
                 }
             }
 
             #(#classes)*
-            #(#enums)*
+            #enums_and_exceptions
             #(#procedures)*
         }
     }
@@ -156,19 +222,39 @@ fn generate_enum_variant_definitions(json: &Value) -> TokenSet<'_> {
             .unwrap()
             .iter()
             .map(|value| {
-                format_ident!(
+                let ident = format_ident!(
                     "{}",
                     value.get("name").unwrap().as_str().unwrap()
-                )
-            })
-            .map(|ident| quote!(#ident)),
+                );
+                let wire = proc_macro2::Literal::i64_unsuffixed(
+                    value.get("value").unwrap().as_i64().unwrap(),
+                );
+                quote!(#ident = #wire)
+            }),
     )
 }
 
+fn generate_exception_definitions(
+    json: &Value,
+    service_name: &str,
+) -> TokenStream {
+    let exceptions = json
+        .get("exceptions")
+        .unwrap()
+        .as_object()
+        .unwrap()
+        .keys()
+        .map(|name| format_ident!("{name}"));
+    quote! {
+        crate::schema::rpc_exception!(#service_name, [#(#exceptions,)*]);
+    }
+}
+
 fn generate_procedure_definitions<'a>(
     json: &'a Value,
     service_name: &'a str,
     q_service_name: &'a Ident,
+    asynchronous: bool,
 ) -> TokenSet<'a> {
     Box::new(
         json.get("procedures")
@@ -176,14 +262,22 @@ fn generate_procedure_definitions<'a>(
             .as_object()
             .unwrap()
             .iter()
-            .map(|(name, definition)| {
-                generate_procedure_definition(
+            .map(move |(name, definition)| {
+                let procedure = generate_procedure_definition(
                     name,
                     definition,
                     service_name,
                     q_service_name,
-                )
-                .to_token_stream()
+                    asynchronous,
+                );
+                if has_mutable_key(definition) {
+                    quote! {
+                        #[allow(clippy::mutable_key_type)]
+                        #procedure
+                    }
+                } else {
+                    procedure.to_token_stream()
+                }
             }),
     )
 }
@@ -193,86 +287,163 @@ fn generate_procedure_definition(
     definition: &Value,
     service_name: &str,
     q_service_name: &Ident,
+    asynchronous: bool,
 ) -> impl ToTokens {
     let name_tokens = name.split('_').collect::<Vec<&str>>();
     let class_name = get_struct(&name_tokens);
 
     let fn_name = get_fn_name(&name_tokens, &class_name);
+    let builder_name = match &class_name {
+        Some(class) => {
+            format_ident!("{class}{}Builder", fn_name.to_case(Case::Pascal))
+        }
+        None => format_ident!("{}Builder", fn_name.to_case(Case::Pascal)),
+    };
     let q_class_name = class_name.unwrap_or_else(|| q_service_name.clone());
 
     let Parameters {
         names,
         types,
         as_args,
+        optional_names,
+        optional_types,
+        optional_args,
     } = Parameters::from_json(definition);
 
     let call_name = format_ident!("{fn_name}_call");
     let stream_name = format_ident!("{fn_name}_stream");
-    let fn_name = format_ident!("{fn_name}");
+    let fn_name = format_ident!("{}", rewrite_keywords(fn_name));
     let ret = get_return_type(definition);
-    if env::var("CARGO_FEATURE_TOKIO").is_ok() {
+
+    // Static procedures don't need an instance of the class, so
+    // they become associated functions taking the client, and
+    // use its call timeout unless set with `Call::builder`.
+    let (call_receiver, receiver, call_path, client, timeout) =
+        if is_static(&name_tokens) {
+            (
+                quote!(),
+                quote!(client: &::std::sync::Arc<crate::Client>,),
+                quote!(Self::#call_name),
+                quote!(client),
+                quote!(None),
+            )
+        } else {
+            (
+                quote!(&self,),
+                quote!(&self,),
+                quote!(self.#call_name),
+                quote!(self.client),
+                quote!(self.timeout),
+            )
+        };
+
+    // Async procedures call the `_async` counterparts of the
+    // client's blocking functions.
+    let (asyncness, dot_await, suffix) = if asynchronous {
+        (quote!(async), quote!(.await), "_async")
+    } else {
+        (quote!(), quote!(), "")
+    };
+    let new_stream = format_ident!("new{suffix}");
+    let call_with_timeout = format_ident!("call_with_timeout{suffix}");
+    let builder_stream = format_ident!("stream{suffix}");
+    let builder_call = format_ident!("call{suffix}");
+
+    let call_fn = quote! {
+        pub fn #call_name(
+            #call_receiver #(#names: #types),*
+        ) -> Result<crate::call::Call<#ret>, RpcError> {
+            Ok(crate::call::Call::new(
+                crate::client::Client::proc_call(
+                    #service_name,
+                    #name,
+                    vec![#(#as_args?),*]
+                )
+            ))
+        }
+    };
+
+    let stream_fn = quote! {
+        pub #asyncness fn #stream_name(
+            #receiver #(#names: #types),*
+        ) -> Result<crate::stream::Stream<#ret>, RpcError> {
+            crate::stream::Stream::#new_stream(
+                #client.clone(),
+                #call_path(#(#names),*)?
+            )#dot_await
+        }
+    };
+
+    if optional_names.is_empty() {
         quote! {
             impl #q_class_name {
-                pub(crate) fn #call_name(
-                    &self, #(#names: #types),*
-                ) -> Result<crate::schema::ProcedureCall, RpcError> {
-                    Ok(crate::client::Client::proc_call(
-                        #service_name,
-                        #name,
-                        vec![#(#as_args),*]
-                    ))
-                }
+                #call_fn
 
-                pub async fn #stream_name(
-                    &self, #(#names: #types),*
-                ) -> Result<crate::stream::Stream<#ret>, RpcError> {
-                    crate::stream::Stream::new(
-                        self.client.clone(),
-                        self.#call_name(#(#names),*)?
-                    ).await
-                }
+                #stream_fn
 
-                pub async fn #fn_name(
-                    &self, #(#names: #types),*
+                pub #asyncness fn #fn_name(
+                    #receiver #(#names: #types),*
                 ) -> Result<#ret, RpcError> {
                     let request = crate::schema::Request::from(
-                        self.#call_name(#(#names),*)?);
-                    let response = self.client.call(request).await?;
+                        #call_path(#(#names),*)?);
+                    let response = #client
+                        .#call_with_timeout(request, #timeout)#dot_await?;
 
-                    <#ret>::from_response(response, self.client.clone())
+                    <#ret>::from_response(response, #client.clone())
                 }
             }
         }
     } else {
+        // Trailing parameters with default values are set on a
+        // builder, and left for the server to fill in otherwise.
         quote! {
-            impl #q_class_name {
-                pub(crate) fn #call_name(
-                    &self, #(#names: #types),*
-                ) -> Result<crate::schema::ProcedureCall, RpcError> {
-                    Ok(crate::client::Client::proc_call(
-                        #service_name,
-                        #name,
-                        vec![#(#as_args),*]
-                    ))
+            pub struct #builder_name(crate::call::CallBuilder<#ret>);
+
+            impl #builder_name {
+                #(
+                    pub fn #optional_names(
+                        self, #optional_names: #optional_types
+                    ) -> Self {
+                        Self(self.0.arg(#optional_args))
+                    }
+                )*
+
+                pub fn timeout(self, timeout: ::std::time::Duration) -> Self {
+                    Self(self.0.timeout(timeout))
+                }
+
+                pub fn into_call(
+                    self
+                ) -> Result<crate::call::Call<#ret>, RpcError> {
+                    self.0.into_call()
                 }
 
-                pub fn #stream_name(
-                    &self, #(#names: #types),*
+                pub #asyncness fn stream(
+                    self
                 ) -> Result<crate::stream::Stream<#ret>, RpcError> {
-                    crate::stream::Stream::new(
-                        self.client.clone(),
-                        self.#call_name(#(#names),*)?
-                    )
+                    self.0.#builder_stream()#dot_await
                 }
 
-                pub fn #fn_name(
-                    &self, #(#names: #types),*
-                ) -> Result<#ret, RpcError> {
-                    let request = crate::schema::Request::from(
-                        self.#call_name(#(#names),*)?);
-                    let response = self.client.call(request)?;
+                pub #asyncness fn call(self) -> Result<#ret, RpcError> {
+                    self.0.#builder_call()#dot_await
+                }
+            }
 
-                    <#ret>::from_response(response, self.client.clone())
+            impl #q_class_name {
+                #call_fn
+
+                #stream_fn
+
+                pub fn #fn_name(
+                    #receiver #(#names: #types),*
+                ) -> #builder_name {
+                    #builder_name(
+                        crate::call::CallBuilder::new(
+                            #client.clone(),
+                            #call_path(#(#names),*),
+                            #timeout,
+                        )
+                    )
                 }
             }
         }
@@ -292,6 +463,9 @@ struct Parameters {
     names: Vec<Ident>,
     as_args: Vec<TokenStream>,
     types: Vec<TokenStream>,
+    optional_names: Vec<Ident>,
+    optional_args: Vec<TokenStream>,
+    optional_types: Vec<TokenStream>,
 }
 
 impl Parameters {
@@ -299,6 +473,9 @@ impl Parameters {
         let mut names = Vec::new();
         let mut types = Vec::new();
         let mut args = Vec::new();
+        let mut optional_names = Vec::new();
+        let mut optional_types = Vec::new();
+        let mut optional_args = Vec::new();
 
         let params = json
             .as_object()
@@ -308,6 +485,13 @@ impl Parameters {
             .as_array()
             .unwrap();
 
+        // Only a trailing run of parameters with default values
+        // can be omitted.
+        let first_optional = params
+            .iter()
+            .rposition(|param| param.get("default_value").is_none())
+            .map_or(0, |pos| pos + 1);
+
         for (pos, param_json) in params.iter().enumerate() {
             let param = param_json.as_object().unwrap();
             let name: String = rewrite_keywords(
@@ -319,33 +503,51 @@ impl Parameters {
                     .to_case(Case::Snake),
             );
             if name.eq_ignore_ascii_case("this") {
-                args.push(quote! { self.to_argument(#pos as u32)? });
+                args.push(quote! { self.to_argument(#pos as u32) });
+                continue;
+            }
+
+            let name = format_ident!("{name}");
+            let ty = param.get("type").unwrap().as_object().unwrap();
+            let arg = if ty.get("code").unwrap() == "PROCEDURE_CALL" {
+                quote! {
+                    Into::<crate::schema::ProcedureCall>::into(#name)
+                        .to_argument(#pos as u32)
+                }
             } else {
-                let name = format_ident!("{name}");
-                args.push(quote!(#name.to_argument(#pos as u32)?));
-                names.push(name);
+                quote!(#name.to_argument(#pos as u32))
+            };
 
+            if pos >= first_optional {
+                // Nullable parameters default to null, so they're
+                // set without the `Option` and omitted otherwise.
+                optional_types.push(decode_type(ty, true, false));
+                optional_args.push(arg);
+                optional_names.push(name);
+            } else {
                 let nullable = param
                     .get("nullable")
                     .map(|b| b.as_bool().unwrap())
                     .unwrap_or(false);
-                types.push(decode_type(
-                    param.get("type").unwrap().as_object().unwrap(),
-                    true,
-                    nullable,
-                ));
+                types.push(decode_type(ty, true, nullable));
+                args.push(arg);
+                names.push(name);
             }
         }
         Self {
             names,
             types,
             as_args: args,
+            optional_names,
+            optional_types,
+            optional_args,
         }
     }
 }
 
 fn get_fn_name<T>(proc_tokens: &[&str], class: &Option<T>) -> String {
     match class {
+        Some(_) if is_static(proc_tokens) => &proc_tokens[2..],
         Some(_) => &proc_tokens[1..],
         None => proc_tokens,
     }
@@ -353,6 +555,10 @@ fn get_fn_name<T>(proc_tokens: &[&str], class: &Option<T>) -> String {
     .to_case(Case::Snake)
 }
 
+fn is_static(proc_tokens: &[&str]) -> bool {
+    proc_tokens.len() > 2 && proc_tokens[1] == "static"
+}
+
 fn decode_type(
     ty: &serde_json::Map<String, Value>,
     borrow: bool,
@@ -387,6 +593,7 @@ fn decode_type(
     if borrow {
         type_stream = match code {
             "CLASS" => quote!(&#type_stream),
+            "PROCEDURE_CALL" => quote!(impl Into<#type_stream>),
             _ => type_stream,
         }
     };
@@ -398,6 +605,40 @@ fn decode_type(
     type_stream
 }
 
+/// Whether the procedure takes or returns a set or dictionary
+/// keyed by class instances, which hold the client and so have
+/// interior mutability as far as clippy can tell.
+fn has_mutable_key(definition: &Value) -> bool {
+    fn keyed_by_class(ty: &Value) -> bool {
+        let types = ty.get("types").and_then(Value::as_array);
+        let keyed = matches!(
+            ty.get("code").and_then(Value::as_str),
+            Some("SET" | "DICTIONARY")
+        );
+        (keyed && types.and_then(|t| t.first()).is_some_and(contains_class))
+            || types.into_iter().flatten().any(keyed_by_class)
+    }
+
+    fn contains_class(ty: &Value) -> bool {
+        ty.get("code").and_then(Value::as_str) == Some("CLASS")
+            || ty
+                .get("types")
+                .and_then(Value::as_array)
+                .into_iter()
+                .flatten()
+                .any(contains_class)
+    }
+
+    definition
+        .get("parameters")
+        .and_then(Value::as_array)
+        .into_iter()
+        .flatten()
+        .filter_map(|param| param.get("type"))
+        .chain(definition.get("return_type"))
+        .any(keyed_by_class)
+}
+
 fn get_return_type(definition: &Value) -> TokenStream {
     let mut ret = quote!(());
     if let Some(return_value) = definition.get("return_type") {
@@ -440,8 +681,10 @@ fn decode_class(ty: &serde_json::Map<String, Value>) -> TokenStream {
     );
     let name = format_ident!("{}", ty.get("name").unwrap().as_str().unwrap());
 
+    // Relative to the service module, so that each flavor
+    // refers to its own classes.
     quote!(
-        crate::services::#service::#name
+        super::#service::#name
     )
 }
 
@@ -467,7 +710,12 @@ fn decode_set(ty: &serde_json::Map<String, Value>) -> TokenStream {
 
 fn rewrite_keywords(sample: String) -> String {
     match sample.as_str() {
-        "type" => "r#type".into(),
+        "as" | "async" | "await" | "break" | "const" | "continue" | "do"
+        | "dyn" | "else" | "enum" | "extern" | "false" | "fn" | "for"
+        | "if" | "impl" | "in" | "let" | "loop" | "match" | "mod" | "move"
+        | "mut" | "pub" | "ref" | "return" | "static" | "struct" | "trait"
+        | "true" | "try" | "type" | "unsafe" | "use" | "where" | "while"
+        | "yield" => format!("r#{sample}"),
         _ => sample,
     }
 }
@@ -0,0 +1,212 @@
+use std::{
+    marker::PhantomData,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use crate::{
+    call::Call,
+    client::Client,
+    error::RpcError,
+    schema::{ProcedureCall, ProcedureResult, Request},
+    RpcType,
+};
+
+/// A set of procedure calls sent to the server in a single
+/// request.
+///
+/// `Batch` is created by [`Client::batch`][batch]. Calls are
+/// executed by the server in the order they were added, and
+/// their results are returned together as [`BatchResults`].
+///
+/// Adding a call returns a [`BatchCall`] handle, which
+/// retrieves the call's result with the type of its
+/// [`Call`].
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{services::space_center::SpaceCenter, Client};
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let vessel = SpaceCenter::new(client.clone()).get_active_vessel()?;
+/// let mut batch = client.batch();
+/// let name = batch.add(vessel.get_name_call()?);
+/// let mass = batch.add(vessel.get_mass_call()?);
+/// let results = batch.execute()?;
+///
+/// println!("{} weighs {} kg", results.get(name)?, results.get(mass)?);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [batch]: Client::batch
+pub struct Batch {
+    id: u64,
+    client: Arc<Client>,
+    calls: Vec<ProcedureCall>,
+    timeout: Option<Duration>,
+}
+
+/// A call added to a [`Batch`], returning a result of type
+/// `T`.
+///
+/// Pass it to [`BatchResults::get`] to retrieve the result
+/// once the batch is executed. The handle only retrieves
+/// results of the batch it was added to.
+pub struct BatchCall<T> {
+    batch: u64,
+    index: usize,
+    phantom: PhantomData<fn() -> T>,
+}
+
+/// The results of an executed [`Batch`], in the order the
+/// calls were added.
+pub struct BatchResults {
+    batch: u64,
+    client: Arc<Client>,
+    results: Vec<ProcedureResult>,
+}
+
+impl Batch {
+    pub(crate) fn new(client: Arc<Client>) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        Self {
+            id: NEXT.fetch_add(1, Ordering::Relaxed),
+            client,
+            calls: Vec::new(),
+            timeout: None,
+        }
+    }
+
+    /// Add a procedure call to the batch, returning the handle
+    /// to retrieve its result with.
+    pub fn add<T>(&mut self, call: Call<T>) -> BatchCall<T> {
+        self.calls.push(call.into());
+        BatchCall {
+            batch: self.id,
+            index: self.calls.len() - 1,
+            phantom: PhantomData,
+        }
+    }
+
+    /// Fail the batch with [`RpcError::Timeout`] if the server
+    /// does not respond within `timeout`, instead of the
+    /// client's call timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// The number of calls in the batch.
+    pub fn len(&self) -> usize {
+        self.calls.len()
+    }
+
+    /// Returns `true` if no calls have been added.
+    pub fn is_empty(&self) -> bool {
+        self.calls.is_empty()
+    }
+
+    /// Send every call in the batch to the server in a single
+    /// request.
+    ///
+    /// An error raised by the server for the request as a
+    /// whole is returned here. Errors raised by individual
+    /// procedures are returned by [`BatchResults::get`].
+    pub fn execute(self) -> Result<BatchResults, RpcError> {
+        let count = self.calls.len();
+        let response = self.client.call_with_timeout(
+            Request {
+                calls: self.calls,
+                ..Default::default()
+            },
+            self.timeout,
+        )?;
+
+        BatchResults::new(self.id, self.client, count, response.into_results()?)
+    }
+
+    /// Send every call in the batch to the server in a single
+    /// request, without blocking the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn execute_async(self) -> Result<BatchResults, RpcError> {
+        let count = self.calls.len();
+        let response = self
+            .client
+            .call_with_timeout_async(
+                Request {
+                    calls: self.calls,
+                    ..Default::default()
+                },
+                self.timeout,
+            )
+            .await?;
+
+        BatchResults::new(self.id, self.client, count, response.into_results()?)
+    }
+}
+
+impl BatchResults {
+    fn new(
+        batch: u64,
+        client: Arc<Client>,
+        count: usize,
+        results: Vec<ProcedureResult>,
+    ) -> Result<Self, RpcError> {
+        if results.len() != count {
+            return Err(RpcError::Encoding(
+                "batch result count does not match calls".into(),
+            ));
+        }
+
+        Ok(Self {
+            batch,
+            client,
+            results,
+        })
+    }
+
+    /// Decode the result of `call`, or return the error raised
+    /// by the server while executing it.
+    ///
+    /// Returns [`RpcError::Encoding`] if `call` was added to
+    /// a different batch.
+    pub fn get<T: RpcType>(&self, call: BatchCall<T>) -> Result<T, RpcError> {
+        if call.batch != self.batch {
+            return Err(RpcError::Encoding(
+                "batch call was added to a different batch".into(),
+            ));
+        }
+        self.results
+            .get(call.index)
+            .ok_or(RpcError::Encoding("batch result out of range".into()))?
+            .decode(self.client.clone())
+    }
+
+    /// The number of results in the batch.
+    pub fn len(&self) -> usize {
+        self.results.len()
+    }
+
+    /// Returns `true` if the batch contained no calls.
+    pub fn is_empty(&self) -> bool {
+        self.results.is_empty()
+    }
+}
+
+impl<T> BatchCall<T> {
+    /// The position of the call in its batch.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl<T> Clone for BatchCall<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for BatchCall<T> {}
@@ -0,0 +1,207 @@
+use std::{fmt, marker::PhantomData, sync::Arc, time::Duration};
+
+use crate::{
+    client::Client,
+    error::RpcError,
+    schema::{Argument, FromResponse, ProcedureCall, Request},
+    stream::Stream,
+    RpcType,
+};
+
+/// A procedure call returning a result of type `T`.
+///
+/// `Call<T>` is created by calling any procedure with the
+/// `_call()` suffix. Nothing is sent to the server until the
+/// call is executed, so calls can be composed before use:
+/// added to a [`Batch`][batch], streamed with
+/// [`KRPC::add_stream`][add_stream], or passed to
+/// [`Expression::call`][expression_call].
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{
+///     services::{krpc::KRPC, space_center::SpaceCenter},
+///     Client,
+/// };
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let space_center = SpaceCenter::new(client.clone());
+/// let ut_call = space_center.get_ut_call()?;
+///
+/// let stream = KRPC::new(client).add_stream(ut_call).call()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [batch]: crate::batch::Batch
+/// [add_stream]: crate::services::krpc::KRPC::add_stream
+/// [expression_call]: crate::services::krpc::Expression::call
+pub struct Call<T> {
+    call: ProcedureCall,
+    phantom: PhantomData<fn() -> T>,
+}
+
+impl<T> Call<T> {
+    pub(crate) fn new(call: ProcedureCall) -> Self {
+        Self {
+            call,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The name of the service providing this procedure.
+    pub fn service(&self) -> &str {
+        &self.call.service
+    }
+
+    /// The name of the procedure, e.g. `Vessel_get_Name`.
+    pub fn procedure(&self) -> &str {
+        &self.call.procedure
+    }
+}
+
+/// A procedure call with optional parameters.
+///
+/// Procedures with trailing parameters that have default
+/// values return a builder wrapping `CallBuilder`. Parameters
+/// that are not set are left out of the call, and the server
+/// uses their default values.
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{services::space_center::SpaceCenter, Client};
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let space_center = SpaceCenter::new(client);
+/// space_center
+///     .launch_vessel("VAB".into(), "Probe".into(), "LaunchPad".into())
+///     .recover(false)
+///     .call()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct CallBuilder<T> {
+    client: Arc<Client>,
+    call: Result<Call<T>, RpcError>,
+    timeout: Option<Duration>,
+}
+
+impl<T: RpcType + Send> CallBuilder<T> {
+    pub(crate) fn new(
+        client: Arc<Client>,
+        call: Result<Call<T>, RpcError>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Self {
+            client,
+            call,
+            timeout,
+        }
+    }
+
+    /// Fail the call with [`RpcError::Timeout`] after
+    /// `timeout`, instead of the client's call timeout.
+    ///
+    /// Static procedures take no service handle to set a
+    /// timeout with, so set it on the builder of their call:
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), krpc_client::error::RpcError> {
+    /// use std::time::Duration;
+    ///
+    /// use krpc_client::{services::krpc::Expression, Client};
+    /// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+    /// let one = Expression::constant_double_call(1.0)?
+    ///     .builder(&client)
+    ///     .timeout(Duration::from_millis(100))
+    ///     .call()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set an optional argument, replacing any previously set
+    /// at the same position.
+    pub(crate) fn arg(mut self, argument: Result<Argument, RpcError>) -> Self {
+        self.call = self.call.and_then(|mut call| {
+            let argument = argument?;
+            call.call
+                .arguments
+                .retain(|arg| arg.position != argument.position);
+            call.call.arguments.push(argument);
+            Ok(call)
+        });
+        self
+    }
+
+    /// The procedure call with the arguments set so far.
+    pub fn into_call(self) -> Result<Call<T>, RpcError> {
+        self.call
+    }
+
+    /// Create a stream of the procedure call.
+    pub fn stream(self) -> Result<Stream<T>, RpcError> {
+        Stream::new(self.client, self.call?)
+    }
+
+    /// Create a stream of the procedure call without blocking
+    /// the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn stream_async(self) -> Result<Stream<T>, RpcError> {
+        Stream::new_async(self.client, self.call?).await
+    }
+
+    /// Call the procedure.
+    pub fn call(self) -> Result<T, RpcError> {
+        let response = self
+            .client
+            .call_with_timeout(Request::from(self.call?), self.timeout)?;
+        T::from_response(response, self.client)
+    }
+
+    /// Call the procedure without blocking the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn call_async(self) -> Result<T, RpcError> {
+        let response = self
+            .client
+            .call_with_timeout_async(Request::from(self.call?), self.timeout)
+            .await?;
+        T::from_response(response, self.client)
+    }
+}
+
+impl<T: RpcType + Send> Call<T> {
+    /// A builder to execute the call on `client`, e.g. with a
+    /// [`timeout`][CallBuilder::timeout] of its own.
+    pub fn builder(self, client: &Arc<Client>) -> CallBuilder<T> {
+        CallBuilder::new(client.clone(), Ok(self), None)
+    }
+}
+
+impl<T> Clone for Call<T> {
+    fn clone(&self) -> Self {
+        Self::new(self.call.clone())
+    }
+}
+
+impl<T> fmt::Debug for Call<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Call")
+            .field("service", &self.call.service)
+            .field("procedure", &self.call.procedure)
+            .finish()
+    }
+}
+
+impl<T> From<Call<T>> for ProcedureCall {
+    fn from(call: Call<T>) -> Self {
+        call.call
+    }
+}
+
+impl<T> From<Call<T>> for Request {
+    fn from(call: Call<T>) -> Self {
+        Request::from(call.call)
+    }
+}
@@ -1,18 +1,40 @@
-use std::sync::Arc;
-#[cfg(not(feature = "tokio"))]
-use std::{net::TcpStream, sync::Mutex, thread, time::Duration};
-
-use protobuf::CodedInputStream;
-#[cfg(feature = "tokio")]
-use tokio::{net::TcpStream, sync::Mutex};
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    env, io,
+    net::{TcpStream, ToSocketAddrs},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, Weak,
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+#[cfg(feature = "serial")]
+use std::{
+    io::{Read, Write},
+    sync::atomic::AtomicBool,
+};
 
+#[cfg(feature = "serial")]
+use crate::transport::Polled;
+#[cfg(feature = "websocket")]
+use crate::transport::{query_escape, SharedSocket};
 use crate::{
-    error::RpcError,
+    batch::Batch,
+    error::{ConnectionType, RpcError},
     schema::{
         self, connection_request, connection_response::Status,
-        ConnectionRequest, ConnectionResponse, DecodeUntagged, StreamUpdate,
+        ConnectionRequest, ConnectionResponse, DecodeUntagged,
+    },
+    services::krpc::KRPC,
+    stream::{
+        Callback, CallbackGuard, Listener, Registration, Removing,
+        StreamWrangler,
+    },
+    transport::{
+        multiplexed_handshake, Closer, Device, Reader, Transport, Writer,
     },
-    stream::StreamWrangler,
 };
 
 /// The base kRPC client type.
@@ -33,19 +55,87 @@ use crate::{
 /// [`Client::new`][new] to any RPC service in
 /// [`krpc_client::services::*`][services].
 ///
-/// ```
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
 /// use krpc_client::{services::space_center::SpaceCenter, Client};
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
 /// let space_center = SpaceCenter::new(client);
 /// // Then call procedures with the created service.
 /// println!("Hello, {}!", space_center.get_active_vessel()?.get_name()?);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// With the `tokio` feature, the same services in
+/// `krpc_client::services_async` return futures instead of
+/// blocking. Both can be used with the same client.
+///
+/// ## Handling server errors
+///
+/// Exceptions raised by the server while executing a
+/// procedure are returned as [`RpcError::Server`], which
+/// can be told apart from connection and encoding failures.
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{
+///     error::RpcError, services::space_center::SpaceCenter, Client,
+/// };
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let space_center = SpaceCenter::new(client);
+/// match space_center.get_target_vessel() {
+///     Ok(Some(target)) => println!("Targeting {}", target.get_name()?),
+///     Ok(None) => println!("No target selected"),
+///     Err(RpcError::Server { description, .. }) => println!("{description}"),
+///     Err(e) => return Err(e),
+/// }
+/// # Ok(())
+/// # }
 /// ```
 ///
+/// [`RpcError::Server`]: crate::error::RpcError::Server
+///
 /// [new]: Client::new
 /// [services]: crate::services
 pub struct Client {
-    rpc: Mutex<TcpStream>,
-    stream: Mutex<TcpStream>,
-    streams: StreamWrangler,
+    /// Held only while queueing a request, so calls made
+    /// concurrently wait for their responses together.
+    rpc: Mutex<Writer>,
+    streams: Arc<StreamWrangler>,
+    /// Threads receiving responses and stream updates.
+    readers: Mutex<Vec<JoinHandle<()>>>,
+    /// Handles to shut down the connections the readers are
+    /// receiving from, by the number of the connection.
+    closers: Mutex<Vec<(u64, Closer)>>,
+    /// The number of the current connection, or
+    /// [`RECONNECTING`] while it is being replaced.
+    connection: AtomicU64,
+    /// The number of connections opened.
+    opened: AtomicU64,
+    /// Held while opening a connection to replace the current
+    /// one.
+    reconnecting: Mutex<()>,
+    config: ClientBuilder,
+}
+
+/// The new ids of streams added again after reconnecting, or
+/// the errors adding them, by key.
+type StreamIds = HashMap<u64, Result<u64, RpcError>>;
+
+/// The connection number of a client that lost its connection
+/// and hasn't replaced it yet.
+const RECONNECTING: u64 = u64::MAX;
+
+thread_local! {
+    /// Set on the threads receiving responses and stream
+    /// updates, which run stream callbacks.
+    static READER: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Whether the current thread receives from the server, and
+/// so would never see the response to a call it waits on.
+pub(crate) fn is_reader_thread() -> bool {
+    READER.get()
 }
 
 impl Client {
@@ -57,116 +147,159 @@ impl Client {
     /// use krpc_client::Client;
     /// let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001);
     /// ```
-    #[cfg(not(feature = "tokio"))]
     pub fn new(
         name: &str,
         ip_addr: &str,
         rpc_port: u16,
         stream_port: u16,
     ) -> Result<Arc<Self>, RpcError> {
-        let rpc_request = schema::ConnectionRequest {
-            type_: protobuf::EnumOrUnknown::new(connection_request::Type::RPC),
-            client_name: String::from(name),
-            ..Default::default()
-        };
-        let (rpc_stream, rpc_result) = connect(ip_addr, rpc_port, rpc_request)?;
-
-        let stream_request = schema::ConnectionRequest {
-            type_: protobuf::EnumOrUnknown::new(
-                connection_request::Type::STREAM,
-            ),
-            client_name: String::from(name),
-            client_identifier: rpc_result.client_identifier,
-            ..Default::default()
-        };
-        let (stream_stream, _) = connect(ip_addr, stream_port, stream_request)?;
-
-        let client = Arc::new(Self {
-            rpc: Mutex::new(rpc_stream),
-            stream: Mutex::new(stream_stream),
-            streams: StreamWrangler::default(),
-        });
-
-        // Spawn a thread to receive stream updates.
-        let bg_client = client.clone();
-        thread::spawn(move || loop {
-            bg_client.update_streams().ok();
-        });
-
-        Ok(client)
+        Self::builder()
+            .name(name)
+            .host(ip_addr)
+            .rpc_port(rpc_port)
+            .stream_port(stream_port)
+            .connect()
     }
 
-    /// Constructs a new `Client`.
+    /// Constructs a new `Client` without blocking the
+    /// current task.
     ///
     /// # Examples
     ///
-    /// ```
+    /// ```no_run
+    /// # async fn run() -> Result<(), krpc_client::error::RpcError> {
     /// use krpc_client::Client;
-    /// let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001);
+    /// let client =
+    ///     Client::new_async("Test KRPC", "127.0.0.1", 50000, 50001).await?;
+    /// # Ok(())
+    /// # }
     /// ```
     #[cfg(feature = "tokio")]
-    pub async fn new(
+    pub async fn new_async(
         name: &str,
         ip_addr: &str,
         rpc_port: u16,
         stream_port: u16,
     ) -> Result<Arc<Self>, RpcError> {
-        let rpc_request = schema::ConnectionRequest {
-            type_: protobuf::EnumOrUnknown::new(connection_request::Type::RPC),
-            client_name: String::from(name),
-            ..Default::default()
-        };
-        let (rpc_stream, rpc_result) =
-            connect(ip_addr, rpc_port, rpc_request).await?;
-
-        let stream_request = schema::ConnectionRequest {
-            type_: protobuf::EnumOrUnknown::new(
-                connection_request::Type::STREAM,
-            ),
-            client_name: String::from(name),
-            client_identifier: rpc_result.client_identifier,
-            ..Default::default()
-        };
-        let (stream_stream, _) =
-            connect(ip_addr, stream_port, stream_request).await?;
+        Self::builder()
+            .name(name)
+            .host(ip_addr)
+            .rpc_port(rpc_port)
+            .stream_port(stream_port)
+            .connect_async()
+            .await
+    }
 
-        let client = Arc::new(Self {
-            rpc: Mutex::new(rpc_stream),
-            stream: Mutex::new(stream_stream),
-            streams: StreamWrangler::default(),
-        });
+    /// Configure a new `Client` with a [`ClientBuilder`].
+    ///
+    /// The address and ports default to the `KRPC_ADDRESS`,
+    /// `KRPC_RPC_PORT` and `KRPC_STREAM_PORT` environment
+    /// variables if they are set, or to the kRPC server's
+    /// defaults of `127.0.0.1`, `50000` and `50001`. A port
+    /// variable that is not a port number fails
+    /// [`connect`][ClientBuilder::connect].
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), krpc_client::error::RpcError> {
+    /// use std::time::Duration;
+    ///
+    /// use krpc_client::Client;
+    /// let client = Client::builder()
+    ///     .name("Test KRPC")
+    ///     .connect_timeout(Duration::from_secs(5))
+    ///     .call_timeout(Duration::from_secs(1))
+    ///     .nodelay(true)
+    ///     .connect()?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn builder() -> ClientBuilder {
+        ClientBuilder::default()
+    }
 
-        // Spawn a thread to receive stream updates.
-        let bg_client = client.clone();
-        tokio::task::spawn(async move {
-            loop {
-                bg_client.update_streams().await.ok();
-            }
-        });
+    pub(crate) fn call(
+        self: &Arc<Self>,
+        request: schema::Request,
+    ) -> Result<schema::Response, RpcError> {
+        self.call_with_timeout(request, None)
+    }
 
-        Ok(client)
+    #[cfg(feature = "tokio")]
+    pub(crate) async fn call_async(
+        self: &Arc<Self>,
+        request: schema::Request,
+    ) -> Result<schema::Response, RpcError> {
+        self.call_with_timeout_async(request, None).await
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn call(
-        &self,
+    /// Make a call, failing with [`RpcError::Timeout`] after
+    /// `timeout` instead of the client's call timeout if set.
+    pub(crate) fn call_with_timeout(
+        self: &Arc<Self>,
         request: schema::Request,
+        timeout: Option<Duration>,
     ) -> Result<schema::Response, RpcError> {
-        let mut rpc = self.rpc.lock().map_err(|_| RpcError::Client)?;
+        let timeout = timeout.or(self.config.call_timeout);
+        let (reply, connection) = {
+            let rpc = self.rpc.lock().map_err(|_| RpcError::Client)?;
+            (rpc.send(request), self.connection.load(Ordering::SeqCst))
+        };
 
-        send(&mut rpc, request)?;
-        recv(&mut rpc)
+        let result = reply.and_then(|reply| reply.wait(timeout));
+        if let Err(e) = &result {
+            self.call_failed(connection, e);
+        }
+        result
     }
 
+    /// Make a call without blocking the current task, failing
+    /// with [`RpcError::Timeout`] after `timeout` instead of
+    /// the client's call timeout if set.
     #[cfg(feature = "tokio")]
-    pub(crate) async fn call(
-        &self,
+    pub(crate) async fn call_with_timeout_async(
+        self: &Arc<Self>,
         request: schema::Request,
+        timeout: Option<Duration>,
     ) -> Result<schema::Response, RpcError> {
-        let mut rpc = self.rpc.lock().await;
+        let timeout = timeout.or(self.config.call_timeout);
+        // Sending only queues the request, so the lock isn't
+        // held across any I/O.
+        let (reply, connection) = {
+            let rpc = self.rpc.lock().map_err(|_| RpcError::Client)?;
+            (
+                rpc.send_async(request),
+                self.connection.load(Ordering::SeqCst),
+            )
+        };
+
+        let result = match reply {
+            Ok(reply) => reply.wait(timeout).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = &result {
+            self.call_failed(connection, e);
+        }
+        result
+    }
 
-        send(&mut rpc, request).await?;
-        recv(&mut rpc).await
+    /// Drop `connection` after a call on it failed, so the
+    /// readers reconnect in the background. The call's error is
+    /// returned as is, rather than waiting for the server.
+    fn call_failed(&self, connection: u64, error: &RpcError) {
+        if self.config.reconnect.is_some()
+            && !matches!(error, RpcError::Timeout)
+            && self.connection.load(Ordering::SeqCst) == connection
+        {
+            self.close_connection(connection);
+        }
+    }
+
+    /// Start a [`Batch`] of procedure calls to send to the
+    /// server in a single request.
+    pub fn batch(self: &Arc<Self>) -> Batch {
+        Batch::new(self.clone())
     }
 
     pub(crate) fn proc_call(
@@ -182,198 +315,1083 @@ impl Client {
         }
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn update_streams(self: &Arc<Self>) -> Result<(), RpcError> {
-        let mut stream = self.stream.lock()?;
-        let update = recv::<StreamUpdate>(&mut stream)?;
-        for result in update.results {
-            self.streams.insert(
-                result.id,
-                result.result.into_option().ok_or(RpcError::Client)?,
-            )?;
+    /// Check whether the client is still receiving stream
+    /// updates.
+    ///
+    /// Returns [`RpcError::StreamClosed`] with the reason the
+    /// client stopped receiving updates, e.g. because the
+    /// server closed the connection, or
+    /// [`RpcError::NoStreamConnection`] if the client was built
+    /// without a stream connection.
+    pub fn stream_status(&self) -> Result<(), RpcError> {
+        if !self.config.streams {
+            return Err(RpcError::NoStreamConnection);
         }
-        Ok(())
+        self.streams.status()
     }
 
-    #[cfg(feature = "tokio")]
-    pub(crate) fn register_stream(self: &Arc<Self>, stream_id: u64) -> u32 {
-        self.streams.increment_refcount(stream_id)
+    /// Close the connections to the server, and stop receiving
+    /// stream updates.
+    ///
+    /// Anything waiting on a stream is woken with
+    /// [`RpcError::StreamClosed`]. This is called when the
+    /// client is dropped.
+    pub fn close(&self) {
+        // Also stops the readers waiting to reconnect.
+        self.streams.close(None);
+        for (_, closer) in self.closers.lock().unwrap().drain(..) {
+            closer.close();
+        }
+        let readers = std::mem::take(&mut *self.readers.lock().unwrap());
+        for reader in readers {
+            // A reader holds the client while reconnecting, so it
+            // may be the one dropping it.
+            if reader.thread().id() != thread::current().id() {
+                reader.join().ok();
+            }
+        }
     }
 
+    /// Close the connections to the server without blocking
+    /// the current task, and stop receiving stream updates.
+    ///
+    /// Anything waiting on a stream is woken with
+    /// [`RpcError::StreamClosed`].
     #[cfg(feature = "tokio")]
-    pub(crate) fn release_stream(self: &Arc<Self>, stream_id: u64) -> u32 {
-       self.streams.decrement_refcount(stream_id)
+    pub async fn close_async(self: &Arc<Self>) {
+        let client = self.clone();
+        tokio::task::spawn_blocking(move || client.close())
+            .await
+            .ok();
     }
 
-    #[cfg(feature = "tokio")]
-    pub(crate) async fn update_streams(
+    /// Receive responses and stream updates from the `readers`
+    /// of `connection` in the background.
+    fn start_readers(
         self: &Arc<Self>,
+        connection: u64,
+        readers: Vec<Reader>,
     ) -> Result<(), RpcError> {
-        let mut stream = self.stream.lock().await;
-        let update = recv::<StreamUpdate>(&mut stream).await?;
-        for result in update.results {
-            self.streams
-                .insert(
-                    result.id,
-                    result.result.into_option().ok_or(RpcError::Client)?,
-                )
-                .await?;
+        let closers = readers
+            .iter()
+            .map(|reader| Ok((connection, reader.closer()?)))
+            .collect::<Result<Vec<_>, RpcError>>()?;
+        let readers = readers
+            .into_iter()
+            .map(|mut reader| {
+                let client = Arc::downgrade(self);
+                let streams = self.streams.clone();
+                thread::spawn(move || {
+                    READER.set(true);
+                    let error = loop {
+                        if let Err(e) = update_streams(&mut reader, &streams) {
+                            break e;
+                        }
+                    };
+                    // Fail the calls still waiting before
+                    // reconnecting.
+                    drop(reader);
+                    Client::reader_stopped(
+                        &client, &streams, connection, error,
+                    );
+                })
+            })
+            .collect::<Vec<_>>();
+
+        self.closers.lock().unwrap().extend(closers);
+        // The client may have been closed while connecting.
+        if self.streams.status().is_err() {
+            self.close_connection(connection);
         }
+        let mut running = self.readers.lock().unwrap();
+        running.retain(|reader| !reader.is_finished());
+        running.extend(readers);
         Ok(())
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn read_stream<T: DecodeUntagged>(
+    /// Shut down the connection numbered `connection`,
+    /// stopping its readers.
+    fn close_connection(&self, connection: u64) {
+        self.closers.lock().unwrap().retain(|(number, closer)| {
+            if *number == connection {
+                closer.close();
+            }
+            *number != connection
+        });
+    }
+
+    /// Reconnect in the background once a reader of
+    /// `connection` stops, or close the client's streams with
+    /// the `error` that stopped it.
+    fn reader_stopped(
+        client: &Weak<Client>,
+        streams: &StreamWrangler,
+        connection: u64,
+        error: RpcError,
+    ) {
+        let policy = match client.upgrade() {
+            Some(client) if client.config.reconnect.is_some() => {
+                if !client.claim(connection) {
+                    // Another reader is replacing it, or it was
+                    // already replaced.
+                    return;
+                }
+                client.config.reconnect.clone()
+            }
+            _ => None,
+        };
+        match policy {
+            Some(policy) => Client::reconnect(client, streams, &policy),
+            None => streams.close(Some(error)),
+        }
+    }
+
+    /// Take on replacing `connection`, unless it isn't the
+    /// current connection, and stop its other readers.
+    fn claim(&self, connection: u64) -> bool {
+        // Wait for a connection being opened, in case it's this
+        // one.
+        let _reconnecting = self.reconnecting.lock().unwrap();
+        let claimed = self
+            .connection
+            .compare_exchange(
+                connection,
+                RECONNECTING,
+                Ordering::SeqCst,
+                Ordering::SeqCst,
+            )
+            .is_ok();
+        if claimed {
+            self.close_connection(connection);
+        }
+        claimed
+    }
+
+    /// Replace the client's connection following `policy`,
+    /// until connected, closed or out of attempts.
+    ///
+    /// Only a weak handle is kept between attempts, so
+    /// dropping the client stops reconnecting, the same as
+    /// closing it.
+    fn reconnect(
+        client: &Weak<Client>,
+        streams: &StreamWrangler,
+        policy: &ReconnectPolicy,
+    ) {
+        let mut attempt = 0;
+        loop {
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            let result = {
+                let _reconnecting = client.reconnecting.lock().unwrap();
+                streams.status().and_then(|()| client.reopen())
+            };
+            drop(client);
+
+            match result {
+                Ok(()) => return,
+                Err(_) if streams.status().is_err() => return,
+                Err(e) if policy.gives_up(attempt) => {
+                    streams.close(Some(e));
+                    return;
+                }
+                Err(_) => {}
+            }
+            if streams.sleep(policy.delay(attempt)).is_err() {
+                return;
+            }
+            attempt += 1;
+        }
+    }
+
+    /// Open a new connection and add the client's streams to
+    /// it, then make it the current connection.
+    fn reopen(self: &Arc<Self>) -> Result<(), RpcError> {
+        let connection = self.opened.fetch_add(1, Ordering::SeqCst) + 1;
+        let (writer, readers) = self.config.open()?;
+        // Responses to adding the streams again are received by
+        // the new readers, so they are started first, but the
+        // streams' updates are held back until they are moved to
+        // their new ids.
+        self.streams.hold();
+        self.start_readers(connection, readers)?;
+        match self.add_streams(&writer) {
+            Ok(ids) => {
+                self.streams.remap(ids);
+                *self.rpc.lock()? = writer;
+                self.connection.store(connection, Ordering::SeqCst);
+                Ok(())
+            }
+            Err(e) => {
+                self.close_connection(connection);
+                Err(e)
+            }
+        }
+    }
+
+    /// Add the client's streams to a new connection, returning
+    /// their new ids by key, or the error adding each one.
+    fn add_streams(
         self: &Arc<Self>,
-        id: u64,
-    ) -> Result<T, RpcError> {
-        self.streams.get(self.clone(), id)
+        rpc: &Writer,
+    ) -> Result<StreamIds, RpcError> {
+        let registrations = self.streams.registrations();
+        if registrations.is_empty() {
+            return Ok(HashMap::new());
+        }
+
+        let timeout = self.config.call_timeout;
+        let results = rpc
+            .send(schema::Request {
+                calls: registrations
+                    .iter()
+                    .map(|(_, r)| r.add.clone())
+                    .collect(),
+                ..Default::default()
+            })?
+            .wait(timeout)?
+            .into_results()?;
+        let (ids, setup) = self.stream_setup(&registrations, &results)?;
+        if !setup.calls.is_empty() {
+            rpc.send(setup)?.wait(timeout)?.into_results()?;
+        }
+
+        Ok(ids)
     }
 
-    #[cfg(feature = "tokio")]
-    pub(crate) async fn read_stream<T: DecodeUntagged>(
+    /// The new ids of streams added again, and the request to
+    /// start them and restore their rates.
+    fn stream_setup(
         self: &Arc<Self>,
-        id: u64,
-    ) -> Result<T, RpcError> {
-        self.streams.get(self.clone(), id).await
+        registrations: &[(u64, Registration)],
+        results: &[schema::ProcedureResult],
+    ) -> Result<(StreamIds, schema::Request), RpcError> {
+        let krpc = KRPC::new(self.clone());
+        let mut ids = HashMap::new();
+        let mut calls = Vec::new();
+        for ((key, registration), result) in registrations.iter().zip(results) {
+            let id = if registration.event {
+                result
+                    .decode::<schema::Event>(self.clone())
+                    .map(|event| event.stream.id)
+            } else {
+                result
+                    .decode::<schema::Stream>(self.clone())
+                    .map(|stream| stream.id)
+            };
+            let id = match id {
+                Ok(id) => id,
+                Err(e) => {
+                    ids.insert(*key, Err(e));
+                    continue;
+                }
+            };
+
+            ids.insert(*key, Ok(id));
+            if registration.event {
+                calls.push(krpc.start_stream_call(id)?.into());
+            }
+            if let Some(rate) = registration.rate {
+                calls.push(krpc.set_stream_rate_call(id, rate)?.into());
+            }
+        }
+
+        Ok((
+            ids,
+            schema::Request {
+                calls,
+                ..Default::default()
+            },
+        ))
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn remove_stream(
+    pub(crate) fn register_stream(
         self: &Arc<Self>,
         id: u64,
+        add: schema::ProcedureCall,
+        event: bool,
+    ) -> Result<u64, Removing> {
+        self.streams.register(id, add, event)
+    }
+
+    pub(crate) fn retain_stream(&self, key: u64) {
+        self.streams.retain(key)
+    }
+
+    pub(crate) fn release_stream(&self, key: u64) -> bool {
+        self.streams.release(key)
+    }
+
+    pub(crate) fn forget_stream(&self, key: u64) {
+        self.streams.forget(key)
+    }
+
+    pub(crate) fn await_stream_removal(
+        &self,
+        removing: &Removing,
     ) -> Result<(), RpcError> {
-        self.streams.remove(id);
-        Ok(())
+        self.streams.wait_removed(removing)
     }
 
     #[cfg(feature = "tokio")]
-    pub(crate) async fn remove_stream(
-        self: &Arc<Self>,
-        id: u64,
+    pub(crate) async fn await_stream_removal_async(
+        &self,
+        removing: &Removing,
     ) -> Result<(), RpcError> {
-        self.streams.remove(id).await;
-        Ok(())
+        self.streams.wait_removed_async(removing).await
+    }
+
+    pub(crate) fn stream_id(&self, key: u64) -> Result<u64, RpcError> {
+        self.streams.id(key)
+    }
+
+    pub(crate) fn check_stream(&self, key: u64) -> Result<(), RpcError> {
+        self.streams.stream_status(key)
+    }
+
+    pub(crate) fn set_stream_rate(&self, key: u64, hz: f32) {
+        self.streams.set_rate(key, hz)
+    }
+
+    pub(crate) fn read_stream<T: DecodeUntagged>(
+        self: &Arc<Self>,
+        key: u64,
+    ) -> Result<(T, u64), RpcError> {
+        self.streams.get(self.clone(), key)
+    }
+
+    pub(crate) fn add_stream_callback(
+        &self,
+        key: u64,
+        callback: Callback,
+    ) -> Result<CallbackGuard, RpcError> {
+        self.streams.add_callback(key, callback)
+    }
+
+    pub(crate) fn listen_stream(&self, key: u64, listener: Listener) {
+        self.streams.listen(key, listener)
+    }
+
+    pub(crate) fn stream_error(&self, key: u64) -> Option<RpcError> {
+        self.streams.error(key)
+    }
+
+    pub(crate) fn stream_seq(&self, key: u64) -> Result<u64, RpcError> {
+        self.streams.seq(key)
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn await_stream(&self, id: u64) {
-        self.streams.wait(id)
+    pub(crate) fn await_stream(
+        &self,
+        key: u64,
+        after: u64,
+    ) -> Result<u64, RpcError> {
+        self.streams.wait(key, after)
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub(crate) fn await_stream_timeout(&self, id: u64, dur: Duration) {
-        self.streams.wait_timeout(id, dur)
+    pub(crate) fn await_stream_timeout(
+        &self,
+        key: u64,
+        after: u64,
+        dur: Duration,
+    ) -> Result<u64, RpcError> {
+        self.streams.wait_timeout(key, after, dur)
     }
 
     #[cfg(feature = "tokio")]
-    pub(crate) async fn await_stream(&self, id: u64) {
-        self.streams.wait(id).await
+    pub(crate) async fn await_stream_async(
+        &self,
+        key: u64,
+        after: u64,
+    ) -> Result<u64, RpcError> {
+        self.streams.wait_async(key, after).await
     }
 }
 
-#[cfg(not(feature = "tokio"))]
-fn connect(
-    ip_addr: &str,
-    port: u16,
-    request: ConnectionRequest,
-) -> Result<(TcpStream, ConnectionResponse), RpcError> {
-    let mut conn = TcpStream::connect(format!("{ip_addr}:{port}"))
-        .map_err(RpcError::Connection)?;
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+/// Configures and connects a [`Client`].
+///
+/// Created with [`Client::builder`].
+#[derive(Debug, Clone)]
+pub struct ClientBuilder {
+    name: String,
+    host: String,
+    rpc_port: u16,
+    stream_port: u16,
+    /// The port environment variables that could not be
+    /// parsed, and the error to connect with unless the port
+    /// is set.
+    invalid_ports: Vec<(&'static str, String)>,
+    protocol: Protocol,
+    multiplexed: bool,
+    #[cfg(feature = "serial")]
+    serial_port: String,
+    #[cfg(feature = "serial")]
+    baud_rate: u32,
+    connect_timeout: Option<Duration>,
+    call_timeout: Option<Duration>,
+    nodelay: bool,
+    streams: bool,
+    reconnect: Option<ReconnectPolicy>,
+}
+
+const RPC_PORT_VAR: &str = "KRPC_RPC_PORT";
+const STREAM_PORT_VAR: &str = "KRPC_STREAM_PORT";
 
-    send(&mut conn, request)?;
-    let response = recv::<ConnectionResponse>(&mut conn)?;
-    if response.status.value() != Status::OK as i32 {
-        return Err(RpcError::Client);
+/// The port in the environment variable `var`, or `default`
+/// if it is not set.
+pub(crate) fn parse_port(
+    var: &str,
+    value: Result<String, env::VarError>,
+    default: u16,
+) -> Result<u16, String> {
+    match value {
+        Ok(value) => value
+            .parse()
+            .map_err(|_| format!("{var} is not a port number: {value:?}")),
+        Err(env::VarError::NotPresent) => Ok(default),
+        Err(env::VarError::NotUnicode(value)) => {
+            Err(format!("{var} is not a port number: {value:?}"))
+        }
     }
+}
 
-    Ok((conn, response))
+impl Default for ClientBuilder {
+    fn default() -> Self {
+        let mut invalid_ports = Vec::new();
+        let mut port = |var, default| {
+            parse_port(var, env::var(var), default).unwrap_or_else(|error| {
+                invalid_ports.push((var, error));
+                default
+            })
+        };
+        let rpc_port = port(RPC_PORT_VAR, 50000);
+        let stream_port = port(STREAM_PORT_VAR, 50001);
+
+        Self {
+            name: String::new(),
+            host: env::var("KRPC_ADDRESS")
+                .unwrap_or_else(|_| String::from("127.0.0.1")),
+            rpc_port,
+            stream_port,
+            invalid_ports,
+            protocol: Protocol::default(),
+            multiplexed: false,
+            #[cfg(feature = "serial")]
+            serial_port: String::new(),
+            #[cfg(feature = "serial")]
+            baud_rate: 9600,
+            connect_timeout: None,
+            call_timeout: None,
+            nodelay: false,
+            streams: true,
+            reconnect: None,
+        }
+    }
 }
 
-#[cfg(feature = "tokio")]
-async fn connect(
-    ip_addr: &str,
+impl ClientBuilder {
+    /// The client name displayed by the server.
+    pub fn name(mut self, name: impl Into<String>) -> Self {
+        self.name = name.into();
+        self
+    }
+
+    /// The hostname or IP address of the server.
+    pub fn host(mut self, host: impl Into<String>) -> Self {
+        self.host = host.into();
+        self
+    }
+
+    /// The port of the server's RPC connection.
+    pub fn rpc_port(mut self, port: u16) -> Self {
+        self.rpc_port = port;
+        self.invalid_ports.retain(|(var, _)| *var != RPC_PORT_VAR);
+        self
+    }
+
+    /// The port of the server's stream connection.
+    pub fn stream_port(mut self, port: u16) -> Self {
+        self.stream_port = port;
+        self.invalid_ports
+            .retain(|(var, _)| *var != STREAM_PORT_VAR);
+        self
+    }
+
+    /// The protocol the server is configured to use.
+    /// Defaults to [`Protocol::Tcp`].
+    pub fn protocol(mut self, protocol: Protocol) -> Self {
+        self.protocol = protocol;
+        self
+    }
+
+    /// Receive stream updates over the RPC connection instead
+    /// of opening a stream connection, e.g. when only one port
+    /// is reachable through a proxy or tunnel.
+    ///
+    /// The server must accept `MultiplexedRequest` messages
+    /// on its RPC port. Of the kRPC 0.5 servers, only the
+    /// "Protobuf over SerialIO" server does, so the port must
+    /// lead to one, e.g. through a serial to TCP bridge such as
+    /// `ser2net`. The "Protobuf over TCP" and "Protobuf over
+    /// WebSockets" servers reject the connection. Only used
+    /// with [`Protocol::Tcp`].
+    pub fn multiplexed(mut self, multiplexed: bool) -> Self {
+        self.multiplexed = multiplexed;
+        self
+    }
+
+    /// The path of the serial port connected to the server,
+    /// e.g. `/dev/ttyUSB0` or `COM3`, when using
+    /// [`Protocol::SerialIO`].
+    #[cfg(feature = "serial")]
+    pub fn serial_port(mut self, path: impl Into<String>) -> Self {
+        self.serial_port = path.into();
+        self
+    }
+
+    /// The baud rate of the serial port, which must match the
+    /// server's. Defaults to 9600.
+    #[cfg(feature = "serial")]
+    pub fn baud_rate(mut self, baud_rate: u32) -> Self {
+        self.baud_rate = baud_rate;
+        self
+    }
+
+    /// Give up connecting to the server if a connection and
+    /// handshake take longer than `timeout`.
+    pub fn connect_timeout(mut self, timeout: Duration) -> Self {
+        self.connect_timeout = Some(timeout);
+        self
+    }
+
+    /// Fail procedure calls with [`RpcError::Timeout`] if the
+    /// server does not respond within `timeout`.
+    ///
+    /// A response that arrives later is discarded, so the
+    /// client can keep making calls. Override the timeout for
+    /// some calls with the `with_timeout` method of a service
+    /// or object, or with [`Batch::timeout`].
+    pub fn call_timeout(mut self, timeout: Duration) -> Self {
+        self.call_timeout = Some(timeout);
+        self
+    }
+
+    /// Set `TCP_NODELAY` on the connections to the server.
+    pub fn nodelay(mut self, nodelay: bool) -> Self {
+        self.nodelay = nodelay;
+        self
+    }
+
+    /// Whether to open the stream connection. Without it,
+    /// creating a [`Stream`][crate::stream::Stream] or
+    /// [`Event`][crate::stream::Event] fails with
+    /// [`RpcError::NoStreamConnection`].
+    pub fn streams(mut self, streams: bool) -> Self {
+        self.streams = streams;
+        self
+    }
+
+    /// Reconnect to the server with `policy` if the
+    /// connection is lost, e.g. when the server restarts.
+    ///
+    /// Reconnecting adds the client's streams again, so
+    /// existing [`Stream`][crate::stream::Stream] handles keep
+    /// receiving updates. A stream the server fails to add
+    /// again is closed, failing with
+    /// [`RpcError::StreamClosed`] from then on. Reconnecting happens in the
+    /// background, so calls that fail because the connection
+    /// was lost, or that are made before it is replaced, return
+    /// an error right away.
+    pub fn reconnect(mut self, policy: ReconnectPolicy) -> Self {
+        self.reconnect = Some(policy);
+        self
+    }
+
+    /// Connect to the server.
+    ///
+    /// Fails with [`RpcError::Connection`] if `KRPC_RPC_PORT`
+    /// or `KRPC_STREAM_PORT` is set to something other than a
+    /// port number, unless that port is set on the builder.
+    pub fn connect(self) -> Result<Arc<Client>, RpcError> {
+        let (rpc, readers) = self.open()?;
+        self.start(rpc, readers)
+    }
+
+    /// Connect to a server speaking the [`Protocol::SerialIO`]
+    /// protocol over the two halves of a device, e.g. a serial
+    /// port opened with settings of its own, or a pipe to
+    /// another process.
+    ///
+    /// Reads should fail with [`io::ErrorKind::TimedOut`] or
+    /// [`io::ErrorKind::WouldBlock`] every so often while no
+    /// data arrives, so that the client can stop reading once
+    /// closed. The client can't open the device again, so it
+    /// doesn't reconnect.
+    ///
+    /// ```no_run
+    /// # fn main() -> Result<(), Box<dyn std::error::Error>> {
+    /// use std::time::Duration;
+    ///
+    /// use krpc_client::Client;
+    /// let port = serialport::new("/dev/ttyUSB0", 115200)
+    ///     .timeout(Duration::from_millis(100))
+    ///     .open()?;
+    /// let client = Client::builder()
+    ///     .name("RPC Example")
+    ///     .connect_io(port.try_clone()?, port)?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    #[cfg(feature = "serial")]
+    pub fn connect_io(
+        mut self,
+        reader: impl Read + Send + 'static,
+        writer: impl Write + Send + 'static,
+    ) -> Result<Arc<Client>, RpcError> {
+        self.reconnect = None;
+        let rpc = self.handshake_io(Box::new(reader), Box::new(writer))?;
+        let (rpc, readers) = self.split(rpc, None)?;
+        self.start(rpc, readers)
+    }
+
+    fn start(
+        self,
+        rpc: Writer,
+        readers: Vec<Reader>,
+    ) -> Result<Arc<Client>, RpcError> {
+        let client = Arc::new(Client {
+            rpc: Mutex::new(rpc),
+            streams: Arc::default(),
+            readers: Default::default(),
+            closers: Default::default(),
+            connection: AtomicU64::new(0),
+            opened: AtomicU64::new(0),
+            reconnecting: Mutex::new(()),
+            config: self,
+        });
+        client.start_readers(0, readers)?;
+
+        Ok(client)
+    }
+
+    /// Connect to the server without blocking the current
+    /// task.
+    #[cfg(feature = "tokio")]
+    pub async fn connect_async(self) -> Result<Arc<Client>, RpcError> {
+        tokio::task::spawn_blocking(move || self.connect())
+            .await
+            .map_err(|_| RpcError::Client)?
+    }
+
+    /// Open the RPC connection, and the stream connection if
+    /// enabled, returning the writer for requests and the
+    /// readers for responses and stream updates.
+    fn open(&self) -> Result<(Writer, Vec<Reader>), RpcError> {
+        if let Some((_, error)) = self.invalid_ports.first() {
+            return Err(RpcError::Connection(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                error.clone(),
+            )));
+        }
+
+        let (rpc, stream) = match self.protocol {
+            Protocol::Tcp if self.multiplexed => self.open_multiplexed()?,
+            Protocol::Tcp => self.open_tcp()?,
+            #[cfg(feature = "websocket")]
+            Protocol::WebSocket => self.open_websocket()?,
+            #[cfg(feature = "serial")]
+            Protocol::SerialIO => self.open_serial()?,
+        };
+        self.split(rpc, stream)
+    }
+
+    /// Split the connections into the writer for requests and
+    /// the readers for responses and stream updates.
+    fn split(
+        &self,
+        mut rpc: Transport,
+        stream: Option<Transport>,
+    ) -> Result<(Writer, Vec<Reader>), RpcError> {
+        rpc.set_timeout(self.call_timeout)?;
+        let (writer, reader) = rpc.into_rpc()?;
+        let mut readers = vec![reader];
+        if let Some(mut stream) = stream {
+            stream.set_timeout(None)?;
+            readers.push(stream.into_updates()?);
+        }
+
+        Ok((writer, readers))
+    }
+
+    fn open_tcp(&self) -> Result<(Transport, Option<Transport>), RpcError> {
+        let rpc_request = schema::ConnectionRequest {
+            type_: protobuf::EnumOrUnknown::new(connection_request::Type::RPC),
+            client_name: self.name.clone(),
+            ..Default::default()
+        };
+        let (rpc, rpc_result) = connect(self, self.rpc_port, rpc_request)?;
+
+        let stream = if self.streams {
+            let stream_request = schema::ConnectionRequest {
+                type_: protobuf::EnumOrUnknown::new(
+                    connection_request::Type::STREAM,
+                ),
+                client_name: self.name.clone(),
+                client_identifier: rpc_result.client_identifier,
+                ..Default::default()
+            };
+            let (stream, _) = connect(self, self.stream_port, stream_request)?;
+            Some(stream)
+        } else {
+            None
+        };
+
+        Ok((rpc, stream))
+    }
+
+    /// Multiplexed connections receive stream updates over the
+    /// RPC connection, so there is no stream connection.
+    fn open_multiplexed(
+        &self,
+    ) -> Result<(Transport, Option<Transport>), RpcError> {
+        let conn = connect_tcp(self, ConnectionType::Rpc, self.rpc_port)?;
+        let request = schema::ConnectionRequest {
+            type_: protobuf::EnumOrUnknown::new(connection_request::Type::RPC),
+            client_name: self.name.clone(),
+            ..Default::default()
+        };
+        let response = multiplexed_handshake(&mut &conn, &mut &conn, request)?;
+        check_handshake(ConnectionType::Rpc, Some(self.rpc_port), response)?;
+
+        Ok((Transport::Multiplexed(Device::Tcp(conn)), None))
+    }
+
+    /// WebSocket connections pass the client name, or the
+    /// client identifier for the stream connection, in the
+    /// query of the URL instead of a handshake.
+    #[cfg(feature = "websocket")]
+    fn open_websocket(
+        &self,
+    ) -> Result<(Transport, Option<Transport>), RpcError> {
+        let query = format!("name={}", query_escape(self.name.as_bytes()));
+        let mut rpc = connect_websocket(
+            self,
+            ConnectionType::Rpc,
+            self.rpc_port,
+            &query,
+        )?;
+
+        let stream = if self.streams {
+            rpc.send(client_id_request())?;
+            let id = client_id(rpc.recv()?)?;
+            let query = format!("id={}", query_escape(id.as_bytes()));
+            Some(connect_websocket(
+                self,
+                ConnectionType::Stream,
+                self.stream_port,
+                &query,
+            )?)
+        } else {
+            None
+        };
+
+        Ok((rpc, stream))
+    }
+
+    /// The serial server sends procedure results and stream
+    /// updates over the same port, so there is no stream
+    /// connection.
+    #[cfg(feature = "serial")]
+    fn open_serial(&self) -> Result<(Transport, Option<Transport>), RpcError> {
+        let port = serialport::new(&self.serial_port, self.baud_rate)
+            .timeout(SERIAL_POLL_INTERVAL)
+            .open()
+            .map_err(io::Error::from)?;
+        let reader = port.try_clone().map_err(io::Error::from)?;
+
+        Ok((self.handshake_io(Box::new(reader), Box::new(port))?, None))
+    }
+
+    #[cfg(feature = "serial")]
+    fn handshake_io(
+        &self,
+        reader: Box<dyn Read + Send>,
+        writer: Box<dyn Write + Send>,
+    ) -> Result<Transport, RpcError> {
+        let closed = Arc::new(AtomicBool::new(false));
+        let mut reader =
+            Polled::new(reader, closed.clone(), self.connect_timeout);
+        let mut writer = Polled::new(writer, closed, self.connect_timeout);
+
+        let request = schema::ConnectionRequest {
+            type_: protobuf::EnumOrUnknown::new(connection_request::Type::RPC),
+            client_name: self.name.clone(),
+            ..Default::default()
+        };
+        let response =
+            multiplexed_handshake(&mut reader, &mut writer, request)?;
+        check_handshake(ConnectionType::Rpc, None, response)?;
+
+        Ok(Transport::Multiplexed(Device::Io { reader, writer }))
+    }
+}
+
+/// How long a serial port is read before checking whether the
+/// client was closed.
+#[cfg(feature = "serial")]
+const SERIAL_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The protocol the server communicates with, chosen in the
+/// server's settings in game.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Protocol {
+    /// Protobuf over TCP, the server's default.
+    #[default]
+    Tcp,
+    /// Protobuf over WebSockets. Requires the `websocket`
+    /// feature.
+    #[cfg(feature = "websocket")]
+    WebSocket,
+    /// Protobuf over a serial port, set with
+    /// [`ClientBuilder::serial_port`]. Requires the `serial`
+    /// feature.
+    #[cfg(feature = "serial")]
+    SerialIO,
+}
+
+/// How a [`Client`] reconnects after losing its connection
+/// to the server.
+///
+/// The first attempt is made immediately, then the delay
+/// between attempts doubles from the initial delay up to the
+/// maximum. By default, the client waits from 500 ms up to
+/// 30 s between attempts, and never gives up.
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use std::time::Duration;
+///
+/// use krpc_client::{client::ReconnectPolicy, Client};
+/// let client = Client::builder()
+///     .reconnect(
+///         ReconnectPolicy::default()
+///             .backoff(Duration::from_secs(1), Duration::from_secs(10))
+///             .max_attempts(30),
+///     )
+///     .connect()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug, Clone)]
+pub struct ReconnectPolicy {
+    max_attempts: Option<u32>,
+    initial_delay: Duration,
+    max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: None,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+impl ReconnectPolicy {
+    /// Give up after `attempts` failed attempts, and close the
+    /// client.
+    pub fn max_attempts(mut self, attempts: u32) -> Self {
+        self.max_attempts = Some(attempts);
+        self
+    }
+
+    /// Wait `initial` after the first failed attempt, doubling
+    /// the delay after each one up to `max`.
+    pub fn backoff(mut self, initial: Duration, max: Duration) -> Self {
+        self.initial_delay = initial;
+        self.max_delay = max;
+        self
+    }
+
+    fn gives_up(&self, attempt: u32) -> bool {
+        self.max_attempts.is_some_and(|max| attempt + 1 >= max)
+    }
+
+    fn delay(&self, attempt: u32) -> Duration {
+        self.initial_delay
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_delay)
+    }
+}
+
+fn connect(
+    builder: &ClientBuilder,
     port: u16,
     request: ConnectionRequest,
-) -> Result<(TcpStream, ConnectionResponse), RpcError> {
-    let mut conn = TcpStream::connect(format!("{ip_addr}:{port}"))
-        .await
-        .map_err(RpcError::Connection)?;
+) -> Result<(Transport, ConnectionResponse), RpcError> {
+    let connection = connection_type(&request);
+    let mut conn = Transport::Tcp(connect_tcp(builder, connection, port)?);
+
+    let response = conn.handshake(request)?;
+    check_handshake(connection, Some(port), response).map(|r| (conn, r))
+}
 
-    send(&mut conn, request).await?;
-    let response = recv::<ConnectionResponse>(&mut conn).await?;
-    if response.status.value() != Status::OK as i32 {
-        return Err(RpcError::Client);
+#[cfg(feature = "websocket")]
+fn connect_websocket(
+    builder: &ClientBuilder,
+    connection: ConnectionType,
+    port: u16,
+    query: &str,
+) -> Result<Transport, RpcError> {
+    let conn = connect_tcp(builder, connection, port)?;
+    let (ws, _) = tungstenite::client(
+        websocket_url(builder, port, query),
+        SharedSocket::new(conn),
+    )
+    .map_err(|e| RpcError::Connect {
+        connection,
+        port,
+        source: match e {
+            // The handshake was interrupted by the connect
+            // timeout.
+            tungstenite::HandshakeError::Interrupted(_) => {
+                io::ErrorKind::TimedOut.into()
+            }
+            tungstenite::HandshakeError::Failure(e) => websocket_error(e),
+        },
+    })?;
+
+    Ok(Transport::WebSocket(Box::new(ws)))
+}
+
+fn connect_tcp(
+    builder: &ClientBuilder,
+    connection: ConnectionType,
+    port: u16,
+) -> Result<TcpStream, RpcError> {
+    let address = (builder.host.as_str(), port);
+    let conn = match builder.connect_timeout {
+        Some(timeout) => connect_timeout(address, timeout),
+        None => TcpStream::connect(address),
     }
+    .map_err(|source| RpcError::Connect {
+        connection,
+        port,
+        source,
+    })?;
+    conn.set_nodelay(builder.nodelay)?;
+    conn.set_read_timeout(builder.connect_timeout)?;
+    conn.set_write_timeout(builder.connect_timeout)?;
 
-    Ok((conn, response))
+    Ok(conn)
 }
 
-#[cfg(not(feature = "tokio"))]
-fn send<T: protobuf::Message>(
-    rpc: &mut TcpStream,
-    message: T,
-) -> Result<(), RpcError> {
-    message
-        .write_length_delimited_to_writer(rpc)
-        .map_err(Into::into)
+fn connect_timeout(
+    address: impl ToSocketAddrs,
+    timeout: Duration,
+) -> io::Result<TcpStream> {
+    let mut error = io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "could not resolve to any addresses",
+    );
+    for address in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&address, timeout) {
+            Ok(conn) => return Ok(conn),
+            Err(e) => error = e,
+        }
+    }
+    Err(error)
 }
 
-#[cfg(feature = "tokio")]
-async fn send<T: protobuf::Message>(
-    rpc: &mut TcpStream,
-    message: T,
-) -> Result<(), RpcError> {
-    use tokio::io::AsyncWriteExt;
+#[cfg(feature = "websocket")]
+fn websocket_url(builder: &ClientBuilder, port: u16, query: &str) -> String {
+    if builder.host.contains(':') {
+        format!("ws://[{}]:{port}/?{query}", builder.host)
+    } else {
+        format!("ws://{}:{port}/?{query}", builder.host)
+    }
+}
 
-    let message = message
-        .write_length_delimited_to_bytes()
-        .map_err(Into::<RpcError>::into)?;
-    rpc.write_all(&message).await.map_err(Into::into)
+#[cfg(feature = "websocket")]
+fn websocket_error(error: tungstenite::Error) -> io::Error {
+    match error {
+        tungstenite::Error::Io(e) => e,
+        e => io::Error::other(e),
+    }
 }
 
-#[cfg(not(feature = "tokio"))]
-fn recv<T: protobuf::Message + Default>(
-    rpc: &mut TcpStream,
-) -> Result<T, RpcError> {
-    CodedInputStream::new(rpc)
-        .read_message()
-        .map_err(Into::into)
+/// The request for the client identifier, which a WebSocket
+/// stream connection passes to the server.
+#[cfg(feature = "websocket")]
+fn client_id_request() -> schema::Request {
+    schema::Request {
+        calls: vec![Client::proc_call("KRPC", "GetClientID", Vec::new())],
+        ..Default::default()
+    }
 }
 
-#[cfg(feature = "tokio")]
-async fn recv<T: protobuf::Message + Default>(
-    rpc: &mut TcpStream,
-) -> Result<T, RpcError> {
-    use bytes::{Buf, BytesMut};
-    use tokio::io::AsyncReadExt;
+/// The base64 encoded client identifier from the response to
+/// [`client_id_request`].
+#[cfg(feature = "websocket")]
+fn client_id(response: schema::Response) -> Result<String, RpcError> {
+    use base64::Engine;
 
-    let mut buffer = BytesMut::new();
-    while buffer.is_empty() {
-        rpc.read_buf(&mut buffer)
-            .await
-            .map_err(Into::<RpcError>::into)?;
+    let result = response
+        .into_results()?
+        .into_iter()
+        .next()
+        .ok_or(RpcError::Encoding("missing procedure result".into()))?;
+    if let Some(error) = result.error.into_option() {
+        return Err(error.into());
     }
+    let id =
+        protobuf::CodedInputStream::from_bytes(&result.value).read_bytes()?;
 
-    let (length, processed) = {
-        let mut decoder = CodedInputStream::from_bytes(&buffer);
-
-        (
-            decoder
-                .read_raw_varint64()?
-                .try_into()
-                .expect("Should always fit"),
-            decoder.pos().try_into().expect("Should always fit"),
-        )
-    };
+    Ok(base64::engine::general_purpose::STANDARD.encode(id))
+}
 
-    buffer.advance(processed);
+fn update_streams(
+    stream: &mut Reader,
+    streams: &StreamWrangler,
+) -> Result<(), RpcError> {
+    let update = stream.recv_update()?;
+    for result in update.results {
+        streams.insert(
+            result.id,
+            result.result.into_option().ok_or(RpcError::Client)?,
+        )?;
+    }
+    Ok(())
+}
 
-    while buffer.len() < length {
-        rpc.read_buf(&mut buffer)
-            .await
-            .map_err(Into::<RpcError>::into)?;
+fn connection_type(request: &ConnectionRequest) -> ConnectionType {
+    match request.type_.enum_value_or_default() {
+        connection_request::Type::RPC => ConnectionType::Rpc,
+        connection_request::Type::STREAM => ConnectionType::Stream,
     }
+}
 
-    T::parse_from_tokio_bytes(&buffer.freeze()).map_err(Into::into)
+fn check_handshake(
+    connection: ConnectionType,
+    port: Option<u16>,
+    response: ConnectionResponse,
+) -> Result<ConnectionResponse, RpcError> {
+    if response.status.enum_value() == Ok(Status::OK) {
+        Ok(response)
+    } else {
+        Err(RpcError::Handshake {
+            connection,
+            port,
+            status: response.status.into(),
+            message: response.message,
+        })
+    }
 }
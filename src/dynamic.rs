@@ -0,0 +1,410 @@
+use std::{collections::HashMap, sync::Arc};
+
+use protobuf::{CodedInputStream, CodedOutputStream, Message};
+
+use crate::{
+    client::Client,
+    error::RpcError,
+    schema::{
+        type_::TypeCode, Argument, Dictionary, DictionaryEntry, List,
+        Procedure, Request, Set, Tuple, Type,
+    },
+    services::krpc::KRPC,
+};
+
+/// A value of any RPC type, for use with [`DynamicClient`].
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// A null object or nullable value, or the result of a
+    /// procedure that returns nothing.
+    None,
+    Double(f64),
+    Float(f32),
+    SInt32(i32),
+    SInt64(i64),
+    UInt32(u32),
+    UInt64(u64),
+    Bool(bool),
+    String(String),
+    Bytes(Vec<u8>),
+    /// The id of a remote object.
+    Object(u64),
+    /// The wire value of an enumeration.
+    Enum(i32),
+    Tuple(Vec<Value>),
+    List(Vec<Value>),
+    Set(Vec<Value>),
+    Dictionary(Vec<(Value, Value)>),
+    /// A serialized protobuf message, e.g. a `Stream` or
+    /// `Status`.
+    Message(Vec<u8>),
+}
+
+/// A client for calling any procedure by name at runtime.
+///
+/// `DynamicClient` fetches the services available on the
+/// server, and uses their definitions to encode arguments
+/// and decode results. This allows calling procedures from
+/// services that were not compiled into this crate.
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{
+///     dynamic::{DynamicClient, Value},
+///     Client,
+/// };
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let client = DynamicClient::new(client)?;
+/// let vessel =
+///     client.dynamic_call("SpaceCenter", "get_ActiveVessel", &[])?;
+/// let name = client.dynamic_call("SpaceCenter", "Vessel_get_Name", &[vessel])?;
+/// println!("{name:?}");
+/// # Ok(())
+/// # }
+/// ```
+pub struct DynamicClient {
+    client: Arc<Client>,
+    procedures: HashMap<(String, String), Procedure>,
+}
+
+impl DynamicClient {
+    /// Constructs a new `DynamicClient`, fetching the service
+    /// definitions from the server.
+    pub fn new(client: Arc<Client>) -> Result<Self, RpcError> {
+        let services = KRPC::new(client.clone()).get_services()?;
+        Ok(Self::from_services(client, services))
+    }
+
+    /// Constructs a new `DynamicClient`, fetching the service
+    /// definitions from the server without blocking the
+    /// current task.
+    #[cfg(feature = "tokio")]
+    pub async fn new_async(client: Arc<Client>) -> Result<Self, RpcError> {
+        let services = crate::services_async::krpc::KRPC::new(client.clone())
+            .get_services()
+            .await?;
+        Ok(Self::from_services(client, services))
+    }
+
+    fn from_services(
+        client: Arc<Client>,
+        services: crate::schema::Services,
+    ) -> Self {
+        let procedures = services
+            .services
+            .into_iter()
+            .flat_map(|service| {
+                let name = service.name;
+                service.procedures.into_iter().map(move |procedure| {
+                    ((name.clone(), procedure.name.clone()), procedure)
+                })
+            })
+            .collect();
+
+        Self { client, procedures }
+    }
+
+    /// The names of the services available on the server.
+    pub fn services(&self) -> impl Iterator<Item = &str> {
+        let mut services: Vec<&str> =
+            self.procedures.keys().map(|(s, _)| s.as_str()).collect();
+        services.sort_unstable();
+        services.dedup();
+        services.into_iter()
+    }
+
+    /// The names of the procedures provided by `service`.
+    pub fn procedures<'a>(
+        &'a self,
+        service: &'a str,
+    ) -> impl Iterator<Item = &'a str> + 'a {
+        self.procedures
+            .keys()
+            .filter(move |(s, _)| s == service)
+            .map(|(_, procedure)| procedure.as_str())
+    }
+
+    /// Call `procedure` of `service` with `args` in the order of
+    /// the procedure's parameters.
+    ///
+    /// Trailing parameters with default values may be omitted.
+    pub fn dynamic_call(
+        &self,
+        service: &str,
+        procedure: &str,
+        args: &[Value],
+    ) -> Result<Value, RpcError> {
+        let (request, definition) = self.request(service, procedure, args)?;
+        let response = self.client.call(request)?;
+        decode_result(definition, response)
+    }
+
+    /// Call `procedure` of `service` with `args` without
+    /// blocking the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn dynamic_call_async(
+        &self,
+        service: &str,
+        procedure: &str,
+        args: &[Value],
+    ) -> Result<Value, RpcError> {
+        let (request, definition) = self.request(service, procedure, args)?;
+        let response = self.client.call_async(request).await?;
+        decode_result(definition, response)
+    }
+
+    fn request(
+        &self,
+        service: &str,
+        procedure: &str,
+        args: &[Value],
+    ) -> Result<(Request, &Procedure), RpcError> {
+        let definition = self
+            .procedures
+            .get(&(service.to_owned(), procedure.to_owned()))
+            .ok_or_else(|| {
+                RpcError::Encoding(format!(
+                    "unknown procedure {service}.{procedure}"
+                ))
+            })?;
+
+        if args.len() > definition.parameters.len() {
+            return Err(RpcError::Encoding(format!(
+                "too many arguments for {service}.{procedure}"
+            )));
+        }
+
+        let mut arguments = Vec::new();
+        for (position, parameter) in definition.parameters.iter().enumerate() {
+            match args.get(position) {
+                Some(arg) => arguments.push(Argument {
+                    position: position as u32,
+                    value: encode_value(&parameter.type_, arg)?,
+                    ..Default::default()
+                }),
+                None if !parameter.default_value.is_empty() => {}
+                None => {
+                    return Err(RpcError::Encoding(format!(
+                        "missing argument {} for {service}.{procedure}",
+                        parameter.name
+                    )))
+                }
+            }
+        }
+
+        let call = Client::proc_call(service, procedure, arguments);
+        Ok((Request::from(call), definition))
+    }
+}
+
+fn decode_result(
+    definition: &Procedure,
+    response: crate::schema::Response,
+) -> Result<Value, RpcError> {
+    let result = response
+        .into_results()?
+        .into_iter()
+        .next()
+        .ok_or(RpcError::Encoding("missing procedure result".into()))?;
+    if let Some(error) = result.error.into_option() {
+        return Err(error.into());
+    }
+
+    match definition.return_type.as_ref() {
+        Some(ty) if ty.code.enum_value_or_default() != TypeCode::NONE => {
+            if definition.return_is_nullable && result.value == [0u8] {
+                Ok(Value::None)
+            } else {
+                decode_value(ty, &result.value)
+            }
+        }
+        _ => Ok(Value::None),
+    }
+}
+
+pub(crate) fn encode_value(
+    ty: &Type,
+    value: &Value,
+) -> Result<Vec<u8>, RpcError> {
+    let mut buf = Vec::new();
+    {
+        let mut os = CodedOutputStream::vec(&mut buf);
+        match (ty.code.enum_value_or_default(), value) {
+            (_, Value::None) => os.write_uint64_no_tag(0)?,
+            (TypeCode::DOUBLE, Value::Double(v)) => {
+                os.write_double_no_tag(*v)?
+            }
+            (TypeCode::FLOAT, Value::Float(v)) => os.write_float_no_tag(*v)?,
+            (TypeCode::SINT32, Value::SInt32(v)) => {
+                os.write_sint32_no_tag(*v)?
+            }
+            (TypeCode::SINT64, Value::SInt64(v)) => {
+                os.write_sint64_no_tag(*v)?
+            }
+            (TypeCode::UINT32, Value::UInt32(v)) => {
+                os.write_uint32_no_tag(*v)?
+            }
+            (TypeCode::UINT64, Value::UInt64(v)) => {
+                os.write_uint64_no_tag(*v)?
+            }
+            (TypeCode::BOOL, Value::Bool(v)) => os.write_bool_no_tag(*v)?,
+            (TypeCode::STRING, Value::String(v)) => {
+                os.write_string_no_tag(v)?
+            }
+            (TypeCode::BYTES, Value::Bytes(v)) => os.write_bytes_no_tag(v)?,
+            (TypeCode::CLASS, Value::Object(v)) => {
+                os.write_uint64_no_tag(*v)?
+            }
+            (TypeCode::ENUMERATION, Value::Enum(v)) => {
+                os.write_sint32_no_tag(*v)?
+            }
+            (
+                TypeCode::EVENT
+                | TypeCode::PROCEDURE_CALL
+                | TypeCode::STREAM
+                | TypeCode::STATUS
+                | TypeCode::SERVICES,
+                Value::Message(v),
+            ) => os.write_raw_bytes(v)?,
+            (TypeCode::TUPLE, Value::Tuple(values)) => {
+                if values.len() != ty.types.len() {
+                    return Err(RpcError::Encoding(
+                        "tuple has the wrong number of elements".into(),
+                    ));
+                }
+                let items = ty
+                    .types
+                    .iter()
+                    .zip(values)
+                    .map(|(ty, value)| encode_value(ty, value))
+                    .collect::<Result<_, _>>()?;
+                Tuple {
+                    items,
+                    ..Default::default()
+                }
+                .write_to(&mut os)?
+            }
+            (TypeCode::LIST, Value::List(values)) => List {
+                items: encode_items(element_type(ty, 0)?, values)?,
+                ..Default::default()
+            }
+            .write_to(&mut os)?,
+            (TypeCode::SET, Value::Set(values)) => Set {
+                items: encode_items(element_type(ty, 0)?, values)?,
+                ..Default::default()
+            }
+            .write_to(&mut os)?,
+            (TypeCode::DICTIONARY, Value::Dictionary(entries)) => {
+                let (key_type, value_type) =
+                    (element_type(ty, 0)?, element_type(ty, 1)?);
+                let entries = entries
+                    .iter()
+                    .map(|(key, value)| {
+                        Ok(DictionaryEntry {
+                            key: encode_value(key_type, key)?,
+                            value: encode_value(value_type, value)?,
+                            ..Default::default()
+                        })
+                    })
+                    .collect::<Result<_, RpcError>>()?;
+                Dictionary {
+                    entries,
+                    ..Default::default()
+                }
+                .write_to(&mut os)?
+            }
+            (code, value) => {
+                return Err(RpcError::Encoding(format!(
+                    "cannot encode {value:?} as {code:?}"
+                )))
+            }
+        }
+        os.flush()?;
+    }
+
+    Ok(buf)
+}
+
+fn encode_items(ty: &Type, values: &[Value]) -> Result<Vec<Vec<u8>>, RpcError> {
+    values.iter().map(|value| encode_value(ty, value)).collect()
+}
+
+pub(crate) fn decode_value(ty: &Type, buf: &[u8]) -> Result<Value, RpcError> {
+    let mut is = CodedInputStream::from_bytes(buf);
+    let value = match ty.code.enum_value_or_default() {
+        TypeCode::NONE => Value::None,
+        TypeCode::DOUBLE => Value::Double(is.read_double()?),
+        TypeCode::FLOAT => Value::Float(is.read_float()?),
+        TypeCode::SINT32 => Value::SInt32(is.read_sint32()?),
+        TypeCode::SINT64 => Value::SInt64(is.read_sint64()?),
+        TypeCode::UINT32 => Value::UInt32(is.read_uint32()?),
+        TypeCode::UINT64 => Value::UInt64(is.read_uint64()?),
+        TypeCode::BOOL => Value::Bool(is.read_bool()?),
+        TypeCode::STRING => Value::String(is.read_string()?),
+        TypeCode::BYTES => Value::Bytes(is.read_bytes()?),
+        TypeCode::CLASS => match is.read_uint64()? {
+            0 => Value::None,
+            id => Value::Object(id),
+        },
+        TypeCode::ENUMERATION => Value::Enum(is.read_sint32()?),
+        TypeCode::EVENT
+        | TypeCode::PROCEDURE_CALL
+        | TypeCode::STREAM
+        | TypeCode::STATUS
+        | TypeCode::SERVICES => Value::Message(buf.to_vec()),
+        TypeCode::TUPLE => {
+            let items = Tuple::parse_from_bytes(buf)?.items;
+            if items.len() != ty.types.len() {
+                return Err(RpcError::Encoding(
+                    "tuple has the wrong number of elements".into(),
+                ));
+            }
+            Value::Tuple(
+                ty.types
+                    .iter()
+                    .zip(items)
+                    .map(|(ty, item)| decode_value(ty, &item))
+                    .collect::<Result<_, _>>()?,
+            )
+        }
+        TypeCode::LIST => Value::List(decode_items(
+            element_type(ty, 0)?,
+            List::parse_from_bytes(buf)?.items,
+        )?),
+        TypeCode::SET => Value::Set(decode_items(
+            element_type(ty, 0)?,
+            Set::parse_from_bytes(buf)?.items,
+        )?),
+        TypeCode::DICTIONARY => {
+            let (key_type, value_type) =
+                (element_type(ty, 0)?, element_type(ty, 1)?);
+            Value::Dictionary(
+                Dictionary::parse_from_bytes(buf)?
+                    .entries
+                    .into_iter()
+                    .map(|entry| {
+                        Ok((
+                            decode_value(key_type, &entry.key)?,
+                            decode_value(value_type, &entry.value)?,
+                        ))
+                    })
+                    .collect::<Result<_, RpcError>>()?,
+            )
+        }
+    };
+
+    Ok(value)
+}
+
+fn decode_items(
+    ty: &Type,
+    items: Vec<Vec<u8>>,
+) -> Result<Vec<Value>, RpcError> {
+    items.iter().map(|item| decode_value(ty, item)).collect()
+}
+
+fn element_type(ty: &Type, index: usize) -> Result<&Type, RpcError> {
+    ty.types
+        .get(index)
+        .ok_or(RpcError::Encoding("collection type out of range".into()))
+}
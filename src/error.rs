@@ -1,7 +1,12 @@
-use std::{io, sync::PoisonError};
+use std::{
+    fmt, io,
+    sync::{Arc, PoisonError},
+};
 
 use thiserror::Error;
 
+use crate::schema;
+
 /// The `RpcError` error indicates a failure originating
 /// from the server or from the client internally.
 #[derive(Error, Debug)]
@@ -11,6 +16,30 @@ pub enum RpcError {
     #[error("Connection failed")]
     Connection(#[from] io::Error),
 
+    /// `Connect` indicates the client was unable to open the
+    /// RPC or stream connection on `port`, e.g. because the
+    /// connection was refused.
+    #[error("{connection} connection to port {port} failed: {source}")]
+    Connect {
+        connection: ConnectionType,
+        port: u16,
+        source: io::Error,
+    },
+
+    /// `Handshake` indicates the server rejected the RPC or
+    /// stream connection on `port`, or on the serial port if
+    /// `None`, with the status and message from its response.
+    #[error(
+        "{connection} connection{} rejected ({status:?}): {message}",
+        .port.map(|port| format!(" to port {port}")).unwrap_or_default()
+    )]
+    Handshake {
+        connection: ConnectionType,
+        port: Option<u16>,
+        status: HandshakeStatus,
+        message: String,
+    },
+
     /// `Client` errors capture runtime errors from within
     /// the client.
     #[error("Unexpected client error")]
@@ -25,6 +54,124 @@ pub enum RpcError {
     /// messages.
     #[error(transparent)]
     ProtobufError(#[from] protobuf::Error),
+
+    /// `Timeout` indicates the server did not respond to a
+    /// procedure call within the client's call timeout.
+    #[error("Procedure call timed out")]
+    Timeout,
+
+    /// `NoStreamConnection` indicates a stream was requested
+    /// from a client built without a stream connection.
+    #[error("Client has no stream connection")]
+    NoStreamConnection,
+
+    /// `StreamClosed` indicates the client is no longer
+    /// receiving stream updates, either because it was closed
+    /// or because of the `reason` error.
+    #[error("Stream connection closed")]
+    StreamClosed {
+        #[source]
+        reason: Option<Arc<RpcError>>,
+    },
+
+    /// `Server` errors are raised by the server while
+    /// executing a procedure, e.g. calling a procedure that
+    /// requires a target when the vessel has none.
+    #[error("{service}.{name}: {description}")]
+    Server {
+        service: String,
+        name: String,
+        description: String,
+        stack_trace: String,
+    },
+}
+
+impl RpcError {
+    /// Downcast a [`Server`][RpcError::Server] error into the
+    /// exception type of an RPC service, e.g.
+    /// [`mech_jeb::Exception`][crate::services::mech_jeb::Exception].
+    ///
+    /// Returns `None` if this is not a server error, or if the
+    /// exception was not raised by that service.
+    ///
+    /// ```no_run
+    /// # use krpc_client::error::RpcError;
+    /// use krpc_client::services::krpc::Exception;
+    /// # fn check(err: RpcError) {
+    /// match err.as_service_error::<Exception>() {
+    ///     Some(Exception::InvalidOperationException) => {}
+    ///     _ => {}
+    /// }
+    /// # }
+    /// ```
+    pub fn as_service_error<E: ServiceException>(&self) -> Option<E> {
+        match self {
+            RpcError::Server { service, name, .. } => {
+                E::from_server_error(service, name)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// One of the two connections made to the server.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConnectionType {
+    /// The connection used for procedure calls.
+    Rpc,
+    /// The connection used to receive stream updates.
+    Stream,
+}
+
+impl fmt::Display for ConnectionType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionType::Rpc => f.write_str("RPC"),
+            ConnectionType::Stream => f.write_str("Stream"),
+        }
+    }
+}
+
+/// The reason the server gave for rejecting a connection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HandshakeStatus {
+    /// The connection request could not be decoded.
+    MalformedMessage,
+    /// The server timed out waiting for the connection
+    /// request.
+    Timeout,
+    /// The connection request was of the wrong type for the
+    /// port, e.g. a stream connection to the RPC port.
+    WrongType,
+    /// A status not known to this client.
+    Unrecognized(i32),
+}
+
+impl From<protobuf::EnumOrUnknown<schema::connection_response::Status>>
+    for HandshakeStatus
+{
+    fn from(
+        status: protobuf::EnumOrUnknown<schema::connection_response::Status>,
+    ) -> Self {
+        use schema::connection_response::Status;
+
+        match status.enum_value() {
+            Ok(Status::MALFORMED_MESSAGE) => HandshakeStatus::MalformedMessage,
+            Ok(Status::TIMEOUT) => HandshakeStatus::Timeout,
+            Ok(Status::WRONG_TYPE) => HandshakeStatus::WrongType,
+            _ => HandshakeStatus::Unrecognized(status.value()),
+        }
+    }
+}
+
+/// An exception declared by an RPC service.
+///
+/// Implemented by the `Exception` enum generated for each
+/// service module.
+pub trait ServiceException: Sized {
+    /// Match an exception by the service and exception name
+    /// reported by the server.
+    fn from_server_error(service: &str, name: &str) -> Option<Self>;
 }
 
 impl<T> From<PoisonError<T>> for RpcError {
@@ -32,3 +179,24 @@ impl<T> From<PoisonError<T>> for RpcError {
         RpcError::Client
     }
 }
+
+impl From<schema::Error> for RpcError {
+    fn from(error: schema::Error) -> Self {
+        RpcError::Server {
+            service: error.service,
+            name: error.name,
+            description: error.description,
+            stack_trace: error.stack_trace,
+        }
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl From<tungstenite::Error> for RpcError {
+    fn from(error: tungstenite::Error) -> Self {
+        match error {
+            tungstenite::Error::Io(e) => RpcError::Connection(e),
+            e => RpcError::Connection(io::Error::other(e)),
+        }
+    }
+}
@@ -0,0 +1,566 @@
+use std::{
+    collections::{HashMap, HashSet},
+    marker::PhantomData,
+    ops,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+
+use crate::{
+    call::Call,
+    client::Client,
+    error::RpcError,
+    schema::{Argument, DecodeUntagged, EncodeUntagged, ProcedureCall},
+    services::krpc::Expression,
+};
+
+/// A typed expression to be evaluated by the server.
+///
+/// `Expr<T>` builds an expression tree on the client that
+/// evaluates to a value of type `T`. Nothing is sent to the
+/// server until [`build`][build] is called, which creates
+/// the corresponding [`Expression`] using as few requests as
+/// the depth of the tree.
+///
+/// Operands must have the same type, so comparing a `f64`
+/// to a `String` fails to compile. Use [`cast`][cast] to
+/// convert between numeric types.
+///
+/// ```no_run
+/// # fn main() -> Result<(), krpc_client::error::RpcError> {
+/// use krpc_client::{
+///     expr::Expr, services::space_center::SpaceCenter, stream::Event,
+///     Client,
+/// };
+/// # let client = Client::new("Test KRPC", "127.0.0.1", 50000, 50001)?;
+/// let vessel = SpaceCenter::new(client.clone()).get_active_vessel()?;
+/// let flight = vessel.flight().call()?;
+///
+/// let above_10km =
+///     Expr::call(flight.get_mean_altitude_call()?).gt(Expr::f64(10_000.0));
+///
+/// Event::from_expr(client, &above_10km)?.wait()?;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [build]: Expr::build
+/// [cast]: Expr::cast
+pub struct Expr<T> {
+    node: Arc<Node>,
+    phantom: PhantomData<fn() -> T>,
+}
+
+/// A type that can be named in an expression, for casts and
+/// function parameters.
+pub trait ExprType {
+    #[doc(hidden)]
+    const TYPE: &'static str;
+}
+
+/// A numeric type, supporting arithmetic and ordering in
+/// expressions.
+pub trait Numeric: ExprType {
+    /// The result type of [`Expr::average`].
+    type Average;
+}
+
+/// Types supporting `&`, `|` and `^` in expressions.
+pub trait Logical {}
+
+/// Types supporting `<<` and `>>` in expressions.
+pub trait Integral {}
+
+struct Node {
+    procedure: &'static str,
+    args: Vec<Arg>,
+}
+
+enum Arg {
+    Value(Box<dyn EncodeUntagged + Send + Sync>),
+    Node(Arc<Node>),
+    List(Vec<Arc<Node>>),
+}
+
+impl<T> Expr<T> {
+    fn new(procedure: &'static str, args: Vec<Arg>) -> Self {
+        Self {
+            node: Arc::new(Node { procedure, args }),
+            phantom: PhantomData,
+        }
+    }
+
+    fn unary<U>(procedure: &'static str, arg: Expr<T>) -> Expr<U> {
+        Expr::new(procedure, vec![Arg::Node(arg.node)])
+    }
+
+    fn binary<U, V>(
+        procedure: &'static str,
+        lhs: Expr<T>,
+        rhs: Expr<U>,
+    ) -> Expr<V> {
+        Expr::new(procedure, vec![Arg::Node(lhs.node), Arg::Node(rhs.node)])
+    }
+
+    /// An expression evaluating a procedure call, e.g.
+    /// `flight.get_mean_altitude_call()?`.
+    pub fn call(call: Call<T>) -> Self {
+        let call = ProcedureCall::from(call);
+        Self::new("Expression_static_Call", vec![Arg::Value(Box::new(call))])
+    }
+
+    /// `self == other`
+    pub fn eq(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_Equal", self, other)
+    }
+
+    /// `self != other`
+    pub fn ne(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_NotEqual", self, other)
+    }
+
+    /// Create the expression on the server.
+    pub fn build(&self, client: &Arc<Client>) -> Result<Expression, RpcError> {
+        let mut built = HashMap::new();
+        for level in self.node.levels() {
+            let mut batch = client.batch();
+            let mut calls = Vec::new();
+            for node in level.iter() {
+                calls.push(batch.add(node.call(&built)?));
+            }
+            let results = batch.execute()?;
+            for (node, call) in level.iter().zip(calls) {
+                built.insert(node.key(), results.get(call)?);
+            }
+        }
+
+        Expression::decode_untagged(
+            client.clone(),
+            &built[&self.node.key()].encode_untagged()?,
+        )
+    }
+
+    /// Create the expression on the server without blocking
+    /// the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn build_async(
+        &self,
+        client: &Arc<Client>,
+    ) -> Result<crate::services_async::krpc::Expression, RpcError> {
+        let mut built = HashMap::new();
+        for level in self.node.levels() {
+            let mut batch = client.batch();
+            let mut calls = Vec::new();
+            for node in level.iter() {
+                calls.push(batch.add(node.call(&built)?));
+            }
+            let results = batch.execute_async().await?;
+            for (node, call) in level.iter().zip(calls) {
+                built.insert(node.key(), results.get(call)?);
+            }
+        }
+
+        crate::services_async::krpc::Expression::decode_untagged(
+            client.clone(),
+            &built[&self.node.key()].encode_untagged()?,
+        )
+    }
+}
+
+impl<T> Clone for Expr<T> {
+    fn clone(&self) -> Self {
+        Self {
+            node: self.node.clone(),
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl Expr<f64> {
+    /// A constant `f64`.
+    pub fn f64(value: f64) -> Self {
+        Self::constant("Expression_static_ConstantDouble", value)
+    }
+}
+
+impl Expr<f32> {
+    /// A constant `f32`.
+    pub fn f32(value: f32) -> Self {
+        Self::constant("Expression_static_ConstantFloat", value)
+    }
+}
+
+impl Expr<i32> {
+    /// A constant `i32`.
+    pub fn i32(value: i32) -> Self {
+        Self::constant("Expression_static_ConstantInt", value)
+    }
+}
+
+impl Expr<bool> {
+    /// A constant `bool`.
+    pub fn bool(value: bool) -> Self {
+        Self::constant("Expression_static_ConstantBool", value)
+    }
+}
+
+impl Expr<String> {
+    /// A constant `String`.
+    pub fn string(value: impl Into<String>) -> Self {
+        Self::constant("Expression_static_ConstantString", value.into())
+    }
+}
+
+impl<T: EncodeUntagged + Send + Sync + 'static> Expr<T> {
+    fn constant(procedure: &'static str, value: T) -> Self {
+        Self::new(procedure, vec![Arg::Value(Box::new(value))])
+    }
+}
+
+impl<T: ExprType> Expr<T> {
+    /// A function parameter with a unique name.
+    fn parameter() -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let name = format!("p{}", NEXT.fetch_add(1, Ordering::Relaxed));
+        Self::new(
+            "Expression_static_Parameter",
+            vec![Arg::Value(Box::new(name)), Arg::Node(type_node::<T>())],
+        )
+    }
+}
+
+impl<T: Numeric> Expr<T> {
+    /// `self > other`
+    pub fn gt(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_GreaterThan", self, other)
+    }
+
+    /// `self >= other`
+    pub fn ge(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_GreaterThanOrEqual", self, other)
+    }
+
+    /// `self < other`
+    pub fn lt(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_LessThan", self, other)
+    }
+
+    /// `self <= other`
+    pub fn le(self, other: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_LessThanOrEqual", self, other)
+    }
+
+    /// `self` raised to the power `other`.
+    pub fn pow(self, other: Expr<T>) -> Expr<T> {
+        Self::binary("Expression_static_Power", self, other)
+    }
+
+    /// Convert `self` to another numeric type.
+    pub fn cast<U: Numeric>(self) -> Expr<U> {
+        Expr::new(
+            "Expression_static_Cast",
+            vec![Arg::Node(self.node), Arg::Node(type_node::<U>())],
+        )
+    }
+}
+
+impl<T> Expr<Vec<T>> {
+    /// A list of the given expressions.
+    pub fn list(values: Vec<Expr<T>>) -> Self {
+        Self::new(
+            "Expression_static_CreateList",
+            vec![Arg::List(values.into_iter().map(|v| v.node).collect())],
+        )
+    }
+
+    /// The number of items in the collection.
+    pub fn count(self) -> Expr<i32> {
+        Self::unary("Expression_static_Count", self)
+    }
+
+    /// The item at `index`.
+    pub fn get(self, index: Expr<i32>) -> Expr<T> {
+        Self::binary("Expression_static_Get", self, index)
+    }
+
+    /// Whether the collection contains `value`.
+    pub fn contains(self, value: Expr<T>) -> Expr<bool> {
+        Self::binary("Expression_static_Contains", self, value)
+    }
+
+    /// The items of `self` followed by the items of `other`.
+    pub fn concat(self, other: Expr<Vec<T>>) -> Expr<Vec<T>> {
+        Self::binary("Expression_static_Concat", self, other)
+    }
+
+    /// Convert the collection to a set.
+    pub fn to_set(self) -> Expr<HashSet<T>> {
+        Self::unary("Expression_static_ToSet", self)
+    }
+}
+
+impl<T: Numeric> Expr<Vec<T>> {
+    /// The sum of the items in the collection.
+    pub fn sum(self) -> Expr<T> {
+        Self::unary("Expression_static_Sum", self)
+    }
+
+    /// The largest item in the collection.
+    pub fn max(self) -> Expr<T> {
+        Self::unary("Expression_static_Max", self)
+    }
+
+    /// The smallest item in the collection.
+    pub fn min(self) -> Expr<T> {
+        Self::unary("Expression_static_Min", self)
+    }
+
+    /// The average of the items in the collection.
+    pub fn average(self) -> Expr<T::Average> {
+        Self::unary("Expression_static_Average", self)
+    }
+}
+
+impl<T: ExprType> Expr<Vec<T>> {
+    /// Apply `func` to each item in the collection.
+    pub fn select<U>(
+        self,
+        func: impl FnOnce(Expr<T>) -> Expr<U>,
+    ) -> Expr<Vec<U>> {
+        Self::binary("Expression_static_Select", self, function1(func))
+    }
+
+    /// The items in the collection for which `predicate` is
+    /// `true`.
+    pub fn filter(
+        self,
+        predicate: impl FnOnce(Expr<T>) -> Expr<bool>,
+    ) -> Expr<Vec<T>> {
+        Self::binary("Expression_static_Where", self, function1(predicate))
+    }
+
+    /// Whether `predicate` is `true` for every item in the
+    /// collection.
+    pub fn all(
+        self,
+        predicate: impl FnOnce(Expr<T>) -> Expr<bool>,
+    ) -> Expr<bool> {
+        Self::binary("Expression_static_All", self, function1(predicate))
+    }
+
+    /// Whether `predicate` is `true` for any item in the
+    /// collection.
+    pub fn any(
+        self,
+        predicate: impl FnOnce(Expr<T>) -> Expr<bool>,
+    ) -> Expr<bool> {
+        Self::binary("Expression_static_Any", self, function1(predicate))
+    }
+
+    /// The collection sorted by the key returned by `key`.
+    pub fn order_by<K>(
+        self,
+        key: impl FnOnce(Expr<T>) -> Expr<K>,
+    ) -> Expr<Vec<T>> {
+        Self::binary("Expression_static_OrderBy", self, function1(key))
+    }
+
+    /// Combine the items of the collection with `func`,
+    /// starting from the first item.
+    pub fn aggregate(
+        self,
+        func: impl FnOnce(Expr<T>, Expr<T>) -> Expr<T>,
+    ) -> Expr<T> {
+        Self::binary("Expression_static_Aggregate", self, function2(func))
+    }
+
+    /// Combine the items of the collection with `func`,
+    /// starting from `seed`.
+    pub fn aggregate_with_seed<U: ExprType>(
+        self,
+        seed: Expr<U>,
+        func: impl FnOnce(Expr<U>, Expr<T>) -> Expr<U>,
+    ) -> Expr<U> {
+        Expr::new(
+            "Expression_static_AggregateWithSeed",
+            vec![
+                Arg::Node(self.node),
+                Arg::Node(seed.node),
+                Arg::Node(function2(func).node),
+            ],
+        )
+    }
+}
+
+impl<T> Expr<HashSet<T>> {
+    /// Convert the set to a list.
+    pub fn to_list(self) -> Expr<Vec<T>> {
+        Self::unary("Expression_static_ToList", self)
+    }
+}
+
+/// A function of one parameter, `|x| body`.
+fn function1<T: ExprType, U>(
+    body: impl FnOnce(Expr<T>) -> Expr<U>,
+) -> Expr<()> {
+    let x = Expr::<T>::parameter();
+    function(vec![x.node.clone()], body(x).node)
+}
+
+/// A function of two parameters, `|x, y| body`.
+fn function2<T: ExprType, U: ExprType, V>(
+    body: impl FnOnce(Expr<T>, Expr<U>) -> Expr<V>,
+) -> Expr<()> {
+    let x = Expr::<T>::parameter();
+    let y = Expr::<U>::parameter();
+    function(vec![x.node.clone(), y.node.clone()], body(x, y).node)
+}
+
+fn function(parameters: Vec<Arc<Node>>, body: Arc<Node>) -> Expr<()> {
+    Expr::new(
+        "Expression_static_Function",
+        vec![Arg::List(parameters), Arg::Node(body)],
+    )
+}
+
+fn type_node<T: ExprType>() -> Arc<Node> {
+    Arc::new(Node {
+        procedure: T::TYPE,
+        args: Vec::new(),
+    })
+}
+
+impl Node {
+    fn key(self: &Arc<Self>) -> usize {
+        Arc::as_ptr(self) as usize
+    }
+
+    /// Group this node and its descendants by height, so each
+    /// group only depends on the groups before it.
+    fn levels(self: &Arc<Self>) -> Vec<Vec<Arc<Node>>> {
+        fn visit(
+            node: &Arc<Node>,
+            heights: &mut HashMap<usize, usize>,
+            levels: &mut Vec<Vec<Arc<Node>>>,
+        ) -> usize {
+            if let Some(height) = heights.get(&node.key()) {
+                return *height;
+            }
+            let height = node
+                .children()
+                .map(|child| visit(child, heights, levels) + 1)
+                .max()
+                .unwrap_or(0);
+            heights.insert(node.key(), height);
+            if levels.len() <= height {
+                levels.resize_with(height + 1, Vec::new);
+            }
+            levels[height].push(node.clone());
+            height
+        }
+
+        let mut levels = Vec::new();
+        visit(self, &mut HashMap::new(), &mut levels);
+        levels
+    }
+
+    fn children(&self) -> impl Iterator<Item = &Arc<Node>> {
+        self.args.iter().flat_map(|arg| match arg {
+            Arg::Value(_) => [].iter(),
+            Arg::Node(node) => std::slice::from_ref(node).iter(),
+            Arg::List(nodes) => nodes.iter(),
+        })
+    }
+
+    /// The call creating this node, given the ids of the
+    /// objects created for its children. It returns the id of
+    /// the object created.
+    fn call(&self, built: &HashMap<usize, u64>) -> Result<Call<u64>, RpcError> {
+        let mut arguments = Vec::new();
+        for (position, arg) in self.args.iter().enumerate() {
+            let value = match arg {
+                Arg::Value(value) => value.encode_untagged()?,
+                Arg::Node(node) => built[&node.key()].encode_untagged()?,
+                Arg::List(nodes) => nodes
+                    .iter()
+                    .map(|node| built[&node.key()])
+                    .collect::<Vec<u64>>()
+                    .encode_untagged()?,
+            };
+            arguments.push(Argument {
+                position: position as u32,
+                value,
+                ..Default::default()
+            });
+        }
+
+        Ok(Call::new(Client::proc_call(
+            "KRPC",
+            self.procedure,
+            arguments,
+        )))
+    }
+}
+
+macro_rules! expr_type {
+    ($t:ty, $procedure:literal) => {
+        impl ExprType for $t {
+            const TYPE: &'static str = $procedure;
+        }
+    };
+}
+
+expr_type!(f64, "Type_static_Double");
+expr_type!(f32, "Type_static_Float");
+expr_type!(i32, "Type_static_Int");
+expr_type!(bool, "Type_static_Bool");
+expr_type!(String, "Type_static_String");
+
+impl Logical for bool {}
+
+impl Integral for i32 {}
+
+impl Numeric for f64 {
+    type Average = f64;
+}
+
+impl Numeric for f32 {
+    type Average = f32;
+}
+
+impl Numeric for i32 {
+    type Average = f64;
+}
+
+macro_rules! expr_op {
+    ($op:ident, $fname:ident, $procedure:literal, $bound:path) => {
+        impl<T: $bound> ops::$op for Expr<T> {
+            type Output = Expr<T>;
+
+            fn $fname(self, rhs: Expr<T>) -> Expr<T> {
+                Self::binary($procedure, self, rhs)
+            }
+        }
+    };
+}
+
+expr_op!(Add, add, "Expression_static_Add", Numeric);
+expr_op!(Sub, sub, "Expression_static_Subtract", Numeric);
+expr_op!(Mul, mul, "Expression_static_Multiply", Numeric);
+expr_op!(Div, div, "Expression_static_Divide", Numeric);
+expr_op!(Rem, rem, "Expression_static_Modulo", Numeric);
+expr_op!(BitAnd, bitand, "Expression_static_And", Logical);
+expr_op!(BitOr, bitor, "Expression_static_Or", Logical);
+expr_op!(BitXor, bitxor, "Expression_static_ExclusiveOr", Logical);
+expr_op!(Shl, shl, "Expression_static_LeftShift", Integral);
+expr_op!(Shr, shr, "Expression_static_RightShift", Integral);
+
+impl ops::Not for Expr<bool> {
+    type Output = Expr<bool>;
+
+    fn not(self) -> Expr<bool> {
+        Self::unary("Expression_static_Not", self)
+    }
+}
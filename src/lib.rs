@@ -1,6 +1,13 @@
+pub mod batch;
+pub mod call;
 pub mod client;
+pub mod dynamic;
 pub mod error;
+pub mod expr;
 pub mod stream;
+#[cfg(test)]
+mod tests;
+mod transport;
 
 pub use client::Client;
 
@@ -8,6 +15,15 @@ pub mod services {
     include!(concat!(env!("OUT_DIR"), "/services.rs"));
 }
 
+/// The same services as [`services`], with procedures that
+/// return futures instead of blocking.
+///
+/// Enumerations and exceptions are shared with [`services`].
+#[cfg(feature = "tokio")]
+pub mod services_async {
+    include!(concat!(env!("OUT_DIR"), "/services_async.rs"));
+}
+
 mod schema {
     use std::{
         collections::{HashMap, HashSet},
@@ -49,7 +65,38 @@ mod schema {
             response: Response,
             client: Arc<Client>,
         ) -> Result<T, RpcError> {
-            Self::decode_untagged(client, &response.results[0].value)
+            response
+                .into_results()?
+                .first()
+                .ok_or(RpcError::Encoding("missing procedure result".into()))?
+                .decode(client)
+        }
+    }
+
+    impl Response {
+        /// Unwrap the procedure results, or the error raised by
+        /// the server while handling the request.
+        pub(crate) fn into_results(
+            self,
+        ) -> Result<Vec<ProcedureResult>, RpcError> {
+            match self.error.into_option() {
+                Some(error) => Err(error.into()),
+                None => Ok(self.results),
+            }
+        }
+    }
+
+    impl ProcedureResult {
+        /// Decode the result value, or return the error raised
+        /// by the server while executing the procedure.
+        pub(crate) fn decode<T: DecodeUntagged>(
+            &self,
+            client: Arc<Client>,
+        ) -> Result<T, RpcError> {
+            match self.error.as_ref() {
+                Some(error) => Err(error.clone().into()),
+                None => T::decode_untagged(client, &self.value),
+            }
         }
     }
 
@@ -101,7 +148,28 @@ mod schema {
         ($name:ident) => {
             pub struct $name {
                 id: u64,
+                // Unused by classes with only static procedures.
+                #[allow(dead_code)]
                 client: ::std::sync::Arc<crate::client::Client>,
+                #[allow(dead_code)]
+                timeout: Option<::std::time::Duration>,
+            }
+
+            impl $name {
+                /// A handle to the same object whose procedure
+                /// calls fail with [`RpcError::Timeout`] after
+                /// `timeout`, instead of the client's call
+                /// timeout.
+                pub fn with_timeout(
+                    &self,
+                    timeout: ::std::time::Duration,
+                ) -> Self {
+                    $name {
+                        id: self.id,
+                        client: self.client.clone(),
+                        timeout: Some(timeout),
+                    }
+                }
             }
 
             impl crate::schema::DecodeUntagged for $name {
@@ -112,6 +180,7 @@ mod schema {
                     Ok($name {
                         id: u64::decode_untagged(client.clone(), buf)?,
                         client,
+                        timeout: None,
                     })
                 }
             }
@@ -125,27 +194,72 @@ mod schema {
     }
 
     macro_rules! rpc_enum {
-        ($name:ident, [$($value:ident),+$(,)?]) => {
+        ($name:ident, [$($value:ident = $wire:literal),+$(,)?]) => {
             #[derive(Debug, Copy, Clone, PartialEq)]
-            pub enum $name {$(
-                $value,
-            )+}
+            pub enum $name {
+                $($value,)+
+                /// A value unknown to this client, e.g. one added
+                /// by a newer version of the server.
+                ///
+                /// Not named `Unknown`, as some enums, e.g.
+                /// `MapFilterType` and `ResourceConverterState`,
+                /// already have a variant of that name.
+                Unrecognized(i32),
+            }
+
+            impl $name {
+                /// The value representing this variant on the wire.
+                pub fn value(&self) -> i32 {
+                    match self {
+                        $($name::$value => $wire,)+
+                        $name::Unrecognized(value) => *value,
+                    }
+                }
+
+                /// The variant represented by `value` on the wire.
+                pub fn from_value(value: i32) -> Self {
+                    match value {
+                        $($wire => $name::$value,)+
+                        value => $name::Unrecognized(value),
+                    }
+                }
+            }
 
             impl crate::schema::DecodeUntagged for $name {
                 fn decode_untagged(
                     client: ::std::sync::Arc<crate::client::Client>,
                     buf: &[u8]
                 ) -> Result<Self, RpcError> {
-                    match i32::decode_untagged(client, buf)? {
-                        $(i if i == $name::$value as i32 => Ok($name::$value),)+
-                        _ => Err(RpcError::Encoding("invalid enum variant".into()))
-                    }
+                    i32::decode_untagged(client, buf).map($name::from_value)
                 }
             }
 
             impl crate::schema::EncodeUntagged for $name {
                 fn encode_untagged(&self) -> Result<Vec<u8>, RpcError> {
-                    (*self as i32).encode_untagged()
+                    self.value().encode_untagged()
+                }
+            }
+        }
+    }
+
+    macro_rules! rpc_exception {
+        ($service:literal, [$($value:ident),*$(,)?]) => {
+            #[derive(Debug, Copy, Clone, PartialEq, Eq)]
+            pub enum Exception {$(
+                $value,
+            )*}
+
+            impl crate::error::ServiceException for Exception {
+                fn from_server_error(
+                    service: &str,
+                    name: &str
+                ) -> Option<Self> {
+                    match (service, name) {
+                        $(($service, stringify!($value)) => {
+                            Some(Exception::$value)
+                        })*
+                        _ => None,
+                    }
                 }
             }
         }
@@ -540,6 +654,7 @@ mod schema {
     encode_untagged!(u64, write_uint64_no_tag);
 
     pub(crate) use rpc_enum;
+    pub(crate) use rpc_exception;
     pub(crate) use rpc_object;
 }
 
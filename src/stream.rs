@@ -1,18 +1,30 @@
-use std::{collections::HashMap, marker::PhantomData, sync::Arc};
-#[cfg(not(feature = "tokio"))]
 use std::{
-    sync::{Condvar, Mutex},
-    time::Duration,
+    collections::HashMap,
+    marker::PhantomData,
+    panic::{self, AssertUnwindSafe},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, Weak,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+#[cfg(feature = "tokio")]
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
 };
 
 #[cfg(feature = "tokio")]
-use tokio::sync::{Mutex, Notify};
+use tokio::sync::{mpsc::UnboundedReceiver, Notify};
 
 use crate::{
-    client::Client,
+    call::{Call, CallBuilder},
+    client::{self, Client},
     error::RpcError,
-    schema::{DecodeUntagged, ProcedureCall, ProcedureResult},
-    services::krpc::KRPC,
+    expr::Expr,
+    schema::{self, DecodeUntagged, ProcedureCall, ProcedureResult},
+    services::krpc::{Expression, KRPC},
     RpcType,
 };
 
@@ -28,200 +40,794 @@ use crate::{
 /// [`set_rate`][set_rate]. And consumers may block until a
 /// stream's value has changed with [`wait`][wait].
 ///
-/// The stream will attempt to remove itself when dropped.
+/// Each result is numbered by the updates received so far.
+/// [`get_with_seq`][get_with_seq] returns the number with the
+/// result, and [`wait_for_update`][wait_for_update] waits for
+/// a newer one, so an update that arrives in between isn't
+/// missed. Every update wakes all threads and tasks waiting
+/// on the stream.
+///
+/// ```no_run
+/// # use krpc_client::{error::RpcError, stream::Stream};
+/// # fn run(ut: Stream<f64>) -> Result<(), RpcError> {
+/// let mut seq = 0;
+/// loop {
+///     ut.wait_for_update(seq)?;
+///     let (value, latest) = ut.get_with_seq()?;
+///     println!("It's {value} o'clock");
+///     seq = latest;
+/// }
+/// # }
+/// ```
+///
+/// To handle every update in turn, iterate over
+/// [`updates`][updates], or register a callback with
+/// [`on_update`][on_update]. With the `tokio` feature,
+/// `Stream<T>` is also a [`futures_core::Stream`] of its
+/// updates, for use with stream combinators.
+///
+/// Handles to the same procedure call share one stream, as
+/// the server returns the same stream for identical calls,
+/// and so do clones of a handle. The stream will attempt to
+/// remove itself once every handle holding it is dropped.
 /// Otherwise, the server will remove remaining streams when
-/// the client disconnects.
+/// the client disconnects. A handle created while the same
+/// stream is being removed waits for it to be removed, then
+/// adds it again.
 ///
 /// [wait]: Stream::wait
 /// [set_rate]: Stream::set_rate
 /// [get]: Stream::get
+/// [get_with_seq]: Stream::get_with_seq
+/// [wait_for_update]: Stream::wait_for_update
+/// [updates]: Stream::updates
+/// [on_update]: Stream::on_update
 pub struct Stream<T: RpcType + Send> {
-    pub(crate) id: u64,
+    key: u64,
     krpc: KRPC,
     client: Arc<Client>,
+    /// Updates not yet polled, once polled as a
+    /// [`futures_core::Stream`].
+    #[cfg(feature = "tokio")]
+    updates: Option<UnboundedReceiver<ProcedureResult>>,
+    /// Set once polling has ended.
+    #[cfg(feature = "tokio")]
+    ended: bool,
     phantom: PhantomData<T>,
 }
 
-#[cfg(not(feature = "tokio"))]
-type StreamEntry = Arc<(Mutex<ProcedureResult>, Condvar)>;
-#[cfg(feature = "tokio")]
-type StreamEntry = Arc<(Mutex<ProcedureResult>, Notify)>;
+/// A blocking iterator over the updates of a [`Stream`],
+/// created with [`Stream::updates`].
+pub struct Updates<'a, T: RpcType + Send> {
+    stream: &'a Stream<T>,
+    receiver: mpsc::Receiver<ProcedureResult>,
+    ended: bool,
+}
+
+/// A server-side event.
+///
+/// `Event` is created from an [`Expression`] that the server
+/// evaluates, so consumers can block until a condition is met
+/// (e.g. altitude above 10 km) without polling.
+///
+/// The event will attempt to remove itself when dropped, the
+/// same as [`Stream`].
+///
+/// ```no_run
+/// # use std::sync::Arc;
+/// # use krpc_client::{
+/// #     error::RpcError, services::krpc::Expression, stream::Event, Client,
+/// # };
+/// # fn run(
+/// #     client: Arc<Client>,
+/// #     expression: Expression,
+/// # ) -> Result<(), RpcError> {
+/// let event = Event::new(client, &expression)?;
+/// event.wait()?;
+/// println!("The event occurred!");
+/// # Ok(())
+/// # }
+/// ```
+pub struct Event {
+    stream: Stream<bool>,
+}
+
+/// A callback registered with [`Stream::on_update`].
+///
+/// The callback is unregistered when this is dropped, or when
+/// the stream is removed.
+#[must_use = "the callback is unregistered when the guard is dropped"]
+pub struct CallbackGuard {
+    entry: Weak<StreamEntry>,
+    id: u64,
+}
+
+/// The latest result of a stream, and the threads and tasks
+/// waiting for the next.
 #[derive(Default)]
-pub(crate) struct StreamWrangler {
-    streams: Mutex<HashMap<u64, StreamEntry>>,
+struct StreamEntry {
+    latest: Mutex<Latest>,
+    updated: Condvar,
     #[cfg(feature = "tokio")]
-    refcounts: std::sync::Mutex<HashMap<u64, u32>>,
+    notify: Notify,
 }
 
-impl StreamWrangler {
-    #[cfg(feature = "tokio")]
-    pub fn increment_refcount(&self, id: u64) -> u32 {
-        let mut guard = self.refcounts.lock().unwrap();
-        let entry = guard.entry(id).or_insert(0);
-        *entry += 1;
-        *entry
-    }
+#[derive(Default)]
+struct Latest {
+    result: ProcedureResult,
+    /// The number of updates received, which identifies the
+    /// result.
+    seq: u64,
+    /// Queues of handles consuming every update.
+    listeners: Vec<Listener>,
+    /// Callbacks, by the id of their guard.
+    callbacks: Vec<(u64, Callback)>,
+    /// Set once the stream stops receiving updates on its own,
+    /// with the error that stopped it.
+    closed: Option<Option<Arc<RpcError>>>,
+}
+
+pub(crate) type Callback = Arc<Mutex<dyn FnMut(&ProcedureResult) + Send>>;
 
+/// Where a handle consuming every update receives them.
+pub(crate) enum Listener {
+    Blocking(mpsc::Sender<ProcedureResult>),
     #[cfg(feature = "tokio")]
-    pub fn decrement_refcount(&self, id: u64) -> u32 {
-        let mut guard = self.refcounts.lock().unwrap();
-        let Some(entry) = guard.get_mut(&id) else {
-            return 0;
-        };
-        *entry -= 1;
+    Async(tokio::sync::mpsc::UnboundedSender<ProcedureResult>),
+}
 
-        let result = *entry;
-        if result == 0 {
-            guard.remove(&id);
+impl Listener {
+    /// Queue `result`, returning false if the handle has gone.
+    fn send(&self, result: &ProcedureResult) -> bool {
+        match self {
+            Listener::Blocking(tx) => tx.send(result.clone()).is_ok(),
+            #[cfg(feature = "tokio")]
+            Listener::Async(tx) => tx.send(result.clone()).is_ok(),
         }
-
-        result
     }
+}
 
-    #[cfg(not(feature = "tokio"))]
-    pub fn insert(
+#[derive(Default)]
+pub(crate) struct StreamWrangler {
+    /// Latest results, by server stream id.
+    streams: Mutex<HashMap<u64, Arc<StreamEntry>>>,
+    /// Streams held by a [`Stream`], by the handle's key.
+    registrations: Mutex<HashMap<u64, Registration>>,
+    /// Updates received while streams are moved to new ids,
+    /// until they have been moved.
+    held: Mutex<Option<Vec<(u64, ProcedureResult)>>>,
+    next_key: AtomicU64,
+    next_callback: AtomicU64,
+    /// Set once updates stop, with the error that stopped them.
+    closed: Mutex<Option<Option<Arc<RpcError>>>>,
+    /// Notified once updates stop.
+    closing: Condvar,
+}
+
+/// How a stream was added to the server, so it can be added
+/// again after reconnecting.
+#[derive(Clone)]
+pub(crate) struct Registration {
+    pub id: u64,
+    /// The `AddStream` or `AddEvent` call.
+    pub add: ProcedureCall,
+    /// Events are added without starting them.
+    pub event: bool,
+    pub rate: Option<f32>,
+    /// The number of [`Stream`] handles holding the stream.
+    refcount: u32,
+    state: State,
+}
+
+/// Whether a registered stream is on the server.
+#[derive(Clone)]
+enum State {
+    Added,
+    /// Not added again after reconnecting, with the error the
+    /// server returned for it. The stream's id is no longer
+    /// valid.
+    Lost(Option<Arc<RpcError>>),
+    /// Being removed from the server, once no handle holds it.
+    Removing,
+}
+
+/// A stream being removed from the server, returned when
+/// registering a handle for it. The handle waits for the
+/// stream to be removed before adding it again.
+pub(crate) struct Removing(Arc<StreamEntry>);
+
+impl StreamWrangler {
+    /// Track a stream added with `add`, returning the key
+    /// identifying it across reconnections, unless the stream
+    /// is being removed.
+    pub fn register(
         &self,
         id: u64,
-        procedure_result: ProcedureResult,
-    ) -> Result<(), RpcError> {
-        let mut map = self.streams.lock().unwrap();
-        let (lock, cvar) = { &*map.entry(id).or_default().clone() };
+        add: ProcedureCall,
+        event: bool,
+    ) -> Result<u64, Removing> {
+        let mut registrations = self.registrations.lock().unwrap();
+        // The server returns the same stream for identical calls.
+        let found = registrations.iter().find(|(_, registration)| {
+            registration.id == id
+                && !matches!(registration.state, State::Lost(_))
+        });
+        if let Some((
+            _,
+            Registration {
+                state: State::Removing,
+                ..
+            },
+        )) = found
+        {
+            // The pending removal would remove it for this handle
+            // too.
+            let mut map = self.streams.lock().unwrap();
+            return Err(Removing(map.entry(id).or_default().clone()));
+        }
+        let key = found.map(|(key, _)| *key).unwrap_or_else(|| {
+            let key = self.next_key.fetch_add(1, Ordering::Relaxed);
+            registrations.insert(
+                key,
+                Registration {
+                    id,
+                    add,
+                    event,
+                    rate: None,
+                    refcount: 0,
+                    state: State::Added,
+                },
+            );
+            key
+        });
+
+        registrations.get_mut(&key).unwrap().refcount += 1;
 
-        *lock.lock().unwrap() = procedure_result;
-        cvar.notify_one();
+        Ok(key)
+    }
+
+    /// Take another hold on the stream with `key`.
+    pub fn retain(&self, key: u64) {
+        let mut registrations = self.registrations.lock().unwrap();
+        if let Some(registration) = registrations.get_mut(&key) {
+            registration.refcount += 1;
+        }
+    }
 
+    /// Release a handle's hold on the stream with `key`,
+    /// returning whether it should be removed from the server
+    /// now that no handle holds it. If so, it is kept until
+    /// [`forget`][Self::forget] is called once it is removed.
+    pub fn release(&self, key: u64) -> bool {
+        let mut registrations = self.registrations.lock().unwrap();
+        let Some(registration) = registrations.get_mut(&key) else {
+            return false;
+        };
+        registration.refcount -= 1;
+        if registration.refcount > 0 {
+            return false;
+        }
+        if let State::Lost(_) = registration.state {
+            // It's no longer on the server.
+            registrations.remove(&key);
+            return false;
+        }
+        registration.state = State::Removing;
+        true
+    }
+
+    /// Forget the stream with `key` once it has been removed
+    /// from the server, waking handles waiting to add it again.
+    pub fn forget(&self, key: u64) {
+        let mut registrations = self.registrations.lock().unwrap();
+        let Some(registration) = registrations.remove(&key) else {
+            return;
+        };
+        if let State::Removing = registration.state {
+            let mut map = self.streams.lock().unwrap();
+            if let Some(entry) = map.remove(&registration.id) {
+                entry.close(None);
+            }
+        }
+    }
+
+    /// Wait for the stream `removing` to be removed.
+    pub fn wait_removed(&self, removing: &Removing) -> Result<(), RpcError> {
+        let entry = &removing.0;
+        let mut latest = entry.latest.lock().unwrap();
+        while latest.closed.is_none() {
+            self.status()?;
+            latest = entry.updated.wait(latest).unwrap();
+        }
         Ok(())
     }
 
+    /// Wait for the stream `removing` to be removed, without
+    /// blocking.
     #[cfg(feature = "tokio")]
-    pub async fn insert(
+    pub async fn wait_removed_async(
         &self,
-        id: u64,
-        procedure_result: ProcedureResult,
+        removing: &Removing,
     ) -> Result<(), RpcError> {
-        let mut map = self.streams.lock().await;
-        let (lock, cvar) =
-            { &*map.entry(id).or_insert_with(Default::default).clone() };
+        let entry = &removing.0;
+        loop {
+            let notified = entry.notify.notified();
+            if entry.latest.lock().unwrap().closed.is_some() {
+                return Ok(());
+            }
+            self.status()?;
+            notified.await;
+        }
+    }
+
+    /// The server's id for the stream with `key`.
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the stream
+    /// couldn't be added again after reconnecting.
+    pub fn id(&self, key: u64) -> Result<u64, RpcError> {
+        let registrations = self.registrations.lock().unwrap();
+        match registrations.get(&key) {
+            Some(Registration {
+                state: State::Lost(reason),
+                ..
+            }) => Err(RpcError::StreamClosed {
+                reason: reason.clone(),
+            }),
+            Some(registration) => Ok(registration.id),
+            None => Err(RpcError::Client),
+        }
+    }
 
-        *lock.lock().await = procedure_result;
-        cvar.notify_one();
+    /// Check whether the stream with `key` is still receiving
+    /// updates.
+    pub fn stream_status(&self, key: u64) -> Result<(), RpcError> {
+        self.status()?;
+        self.id(key).map(|_| ())
+    }
 
-        Ok(())
+    pub fn set_rate(&self, key: u64, hz: f32) {
+        let mut registrations = self.registrations.lock().unwrap();
+        if let Some(registration) = registrations.get_mut(&key) {
+            registration.rate = Some(hz);
+        }
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub fn wait(&self, id: u64) {
-        let (lock, cvar) = {
-            let mut map = self.streams.lock().unwrap();
-            &*map.entry(id).or_default().clone()
-        };
-        let result = lock.lock().unwrap();
-        let _result = cvar.wait(result).unwrap();
+    /// The streams to add again after reconnecting.
+    pub fn registrations(&self) -> Vec<(u64, Registration)> {
+        let registrations = self.registrations.lock().unwrap();
+        registrations
+            .iter()
+            .filter(|(_, registration)| {
+                matches!(registration.state, State::Added)
+            })
+            .map(|(key, registration)| (*key, registration.clone()))
+            .collect()
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub fn wait_timeout(&self, id: u64, dur: Duration) {
-        let (lock, cvar) = {
+    /// Hold back updates until the streams are moved with
+    /// [`remap`][Self::remap], as the ids they are sent for
+    /// may still belong to other streams. Updates held by an
+    /// earlier call are dropped.
+    pub fn hold(&self) {
+        *self.held.lock().unwrap() = Some(Vec::new());
+    }
+
+    /// Move streams to the ids they were given when added
+    /// again, keeping their latest results and waiters, then
+    /// deliver the updates held back since [`hold`][Self::hold].
+    ///
+    /// Streams without a new id stop receiving updates, with
+    /// the error returned when adding them, if any.
+    pub fn remap(&self, mut ids: HashMap<u64, Result<u64, RpcError>>) {
+        // Updates received meanwhile wait for the held ones.
+        let mut held = self.held.lock().unwrap();
+        {
+            let mut registrations = self.registrations.lock().unwrap();
             let mut map = self.streams.lock().unwrap();
-            &*map.entry(id).or_default().clone()
-        };
-        let result = lock.lock().unwrap();
-        let _result = cvar.wait_timeout(result, dur).unwrap();
+            let mut entries = std::mem::take(&mut *map);
+            for (key, registration) in registrations.iter_mut() {
+                if let State::Lost(_) = registration.state {
+                    continue;
+                }
+                let entry =
+                    entries.remove(&registration.id).unwrap_or_default();
+                match ids.remove(key) {
+                    Some(Ok(id)) => {
+                        registration.id = id;
+                        map.insert(id, entry);
+                    }
+                    error => {
+                        let reason = error.and_then(Result::err).map(Arc::new);
+                        entry.close(reason.clone());
+                        registration.state = State::Lost(reason);
+                    }
+                }
+            }
+        }
+
+        for (id, result) in held.take().unwrap_or_default() {
+            self.deliver(id, result);
+        }
     }
 
-    #[cfg(feature = "tokio")]
-    pub async fn wait(&self, id: u64) {
-        let (_lock, cvar) = {
-            let mut map = self.streams.lock().await;
-            &*map.entry(id).or_insert_with(Default::default).clone()
-        };
-        cvar.notified().await;
+    pub fn status(&self) -> Result<(), RpcError> {
+        match &*self.closed.lock().unwrap() {
+            Some(reason) => Err(RpcError::StreamClosed {
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub fn remove(&self, id: u64) {
-        let mut map = self.streams.lock().unwrap();
-        map.remove(&id);
+    /// Stop waiting for updates, and wake all waiters.
+    /// Returns false if already closed.
+    fn set_closed(&self, reason: Option<RpcError>) -> bool {
+        let mut closed = self.closed.lock().unwrap();
+        if closed.is_some() {
+            return false;
+        }
+        *closed = Some(reason.map(Arc::new));
+        self.closing.notify_all();
+        true
     }
 
-    #[cfg(feature = "tokio")]
-    pub async fn remove(&self, id: u64) {
-        let mut map = self.streams.lock().await;
-        map.remove(&id);
+    /// Sleep for `dur`, or until updates stop, returning why
+    /// they stopped if they have.
+    pub fn sleep(&self, dur: Duration) -> Result<(), RpcError> {
+        let closed = self.closed.lock().unwrap();
+        drop(
+            self.closing
+                .wait_timeout_while(closed, dur, |closed| closed.is_none())
+                .unwrap(),
+        );
+        self.status()
     }
 
-    #[cfg(not(feature = "tokio"))]
-    pub fn get<T: DecodeUntagged>(
+    pub fn close(&self, reason: Option<RpcError>) {
+        if !self.set_closed(reason) {
+            return;
+        }
+        let map = self.streams.lock().unwrap();
+        for entry in map.values() {
+            // Dropping the listeners ends their updates.
+            entry.latest.lock().unwrap().listeners.clear();
+            entry.updated.notify_all();
+            #[cfg(feature = "tokio")]
+            entry.notify.notify_waiters();
+        }
+    }
+
+    pub fn insert(
         &self,
-        client: Arc<Client>,
         id: u64,
-    ) -> Result<T, RpcError> {
+        procedure_result: ProcedureResult,
+    ) -> Result<(), RpcError> {
+        let mut held = self.held.lock().unwrap();
+        match held.as_mut() {
+            Some(held) => held.push((id, procedure_result)),
+            None => {
+                drop(held);
+                self.deliver(id, procedure_result);
+            }
+        }
+        Ok(())
+    }
+
+    /// Store the update of the stream with `id`, waking its
+    /// waiters and calling its callbacks.
+    fn deliver(&self, id: u64, procedure_result: ProcedureResult) {
+        let entry = self.streams.lock().unwrap().entry(id).or_default().clone();
+
+        let mut latest = entry.latest.lock().unwrap();
+        let Latest {
+            result,
+            seq,
+            listeners,
+            callbacks,
+            ..
+        } = &mut *latest;
+        listeners.retain(|listener| listener.send(&procedure_result));
+        let callbacks = callbacks
+            .iter()
+            .map(|(_, callback)| callback.clone())
+            .collect::<Vec<_>>();
+        *result = procedure_result;
+        *seq += 1;
+        // Several handles may be waiting on the same stream.
+        entry.updated.notify_all();
+        #[cfg(feature = "tokio")]
+        entry.notify.notify_waiters();
+
+        // Callbacks run without holding any locks, so they can
+        // read streams.
+        let result = (!callbacks.is_empty()).then(|| latest.result.clone());
+        drop(latest);
+        if let Some(result) = result {
+            for callback in callbacks {
+                // A callback that panicked is poisoned, and
+                // skipped from then on.
+                if let Ok(mut callback) = callback.lock() {
+                    panic::catch_unwind(AssertUnwindSafe(|| callback(&result)))
+                        .ok();
+                }
+            }
+        }
+    }
+
+    fn entry(&self, key: u64) -> Result<Arc<StreamEntry>, RpcError> {
+        let id = self.id(key)?;
         let mut map = self.streams.lock().unwrap();
-        let (lock, _) = { &*map.entry(id).or_default().clone() };
-        let result = lock.lock().unwrap();
-        T::decode_untagged(client, &result.value)
+        Ok(map.entry(id).or_default().clone())
     }
 
+    /// The sequence number of the latest result of the stream
+    /// with `key`.
+    pub fn seq(&self, key: u64) -> Result<u64, RpcError> {
+        Ok(self.entry(key)?.latest.lock().unwrap().seq)
+    }
+
+    /// Wait for a result newer than `after`, returning its
+    /// sequence number.
+    pub fn wait(&self, key: u64, after: u64) -> Result<u64, RpcError> {
+        let entry = self.entry(key)?;
+        let mut latest = entry.latest.lock().unwrap();
+        while latest.seq <= after {
+            self.status()?;
+            latest.status()?;
+            latest = entry.updated.wait(latest).unwrap();
+        }
+        Ok(latest.seq)
+    }
+
+    /// Wait for a result newer than `after`, returning the
+    /// latest sequence number once there is one or `dur` has
+    /// elapsed.
+    pub fn wait_timeout(
+        &self,
+        key: u64,
+        after: u64,
+        dur: Duration,
+    ) -> Result<u64, RpcError> {
+        let deadline = Instant::now() + dur;
+        let entry = self.entry(key)?;
+        let mut latest = entry.latest.lock().unwrap();
+        while latest.seq <= after {
+            self.status()?;
+            latest.status()?;
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            latest = entry
+                .updated
+                .wait_timeout(latest, deadline - now)
+                .unwrap()
+                .0;
+        }
+        Ok(latest.seq)
+    }
+
+    /// Wait for a result newer than `after` without blocking,
+    /// returning its sequence number.
     #[cfg(feature = "tokio")]
-    pub async fn get<T: DecodeUntagged>(
+    pub async fn wait_async(
+        &self,
+        key: u64,
+        after: u64,
+    ) -> Result<u64, RpcError> {
+        let entry = self.entry(key)?;
+        loop {
+            // Woken by any update or close from here on.
+            let notified = entry.notify.notified();
+            {
+                let latest = entry.latest.lock().unwrap();
+                if latest.seq > after {
+                    return Ok(latest.seq);
+                }
+                latest.status()?;
+            }
+            self.status()?;
+            notified.await;
+        }
+    }
+
+    /// Queue every update of the stream with `key` to
+    /// `listener` from now on. The listener is dropped instead
+    /// if updates have stopped.
+    pub fn listen(&self, key: u64, listener: Listener) {
+        let Ok(entry) = self.entry(key) else {
+            return;
+        };
+        let mut latest = entry.latest.lock().unwrap();
+        if self.status().is_ok() && latest.status().is_ok() {
+            latest.listeners.push(listener);
+        }
+    }
+
+    /// Call `callback` with every update of the stream with
+    /// `key` from now on, until the returned guard is dropped.
+    pub fn add_callback(
+        &self,
+        key: u64,
+        callback: Callback,
+    ) -> Result<CallbackGuard, RpcError> {
+        let entry = self.entry(key)?;
+        let id = self.next_callback.fetch_add(1, Ordering::Relaxed);
+        entry.latest.lock().unwrap().callbacks.push((id, callback));
+        Ok(CallbackGuard {
+            entry: Arc::downgrade(&entry),
+            id,
+        })
+    }
+
+    /// Decode the latest result, with its sequence number, or
+    /// return the error the server reported with it.
+    pub fn get<T: DecodeUntagged>(
         &self,
         client: Arc<Client>,
-        id: u64,
-    ) -> Result<T, RpcError> {
-        let mut map = self.streams.lock().await;
-        let (lock, _) =
-            { &*map.entry(id).or_insert_with(Default::default).clone() };
-        let result = lock.lock().await;
-        T::decode_untagged(client, &result.value)
+        key: u64,
+    ) -> Result<(T, u64), RpcError> {
+        let entry = self.entry(key)?;
+        let latest = entry.latest.lock().unwrap();
+        latest
+            .result
+            .decode(client)
+            .map(|value| (value, latest.seq))
+    }
+
+    /// The error the server reported with the latest result of
+    /// the stream with `key`, if any.
+    pub fn error(&self, key: u64) -> Option<RpcError> {
+        let entry = self.entry(key).ok()?;
+        let latest = entry.latest.lock().unwrap();
+        latest
+            .result
+            .error
+            .clone()
+            .into_option()
+            .map(RpcError::from)
+    }
+}
+
+impl StreamEntry {
+    /// Stop the stream receiving updates, and wake all its
+    /// waiters.
+    fn close(&self, reason: Option<Arc<RpcError>>) {
+        let mut latest = self.latest.lock().unwrap();
+        latest.closed = Some(reason);
+        // Dropping the listeners ends their updates.
+        latest.listeners.clear();
+        self.updated.notify_all();
+        #[cfg(feature = "tokio")]
+        self.notify.notify_waiters();
+    }
+}
+
+impl Latest {
+    fn status(&self) -> Result<(), RpcError> {
+        match &self.closed {
+            Some(reason) => Err(RpcError::StreamClosed {
+                reason: reason.clone(),
+            }),
+            None => Ok(()),
+        }
     }
 }
 
 impl<T: RpcType + Send> Stream<T> {
-    #[cfg(not(feature = "tokio"))]
     pub(crate) fn new(
         client: Arc<Client>,
-        call: ProcedureCall,
+        call: Call<T>,
     ) -> Result<Self, RpcError> {
+        client.stream_status()?;
         let krpc = KRPC::new(client.clone());
-        let stream = krpc.add_stream(call, true)?;
-        client.await_stream(stream.id);
+        let add = krpc.add_stream(call).into_call()?;
+        let stream = loop {
+            let stream: schema::Stream =
+                CallBuilder::new(client.clone(), Ok(add.clone()), None)
+                    .call()?;
+            match Self::register(&client, stream.id, add.clone().into(), false)
+            {
+                Ok(stream) => break stream,
+                Err(removing) => client.await_stream_removal(&removing)?,
+            }
+        };
+        stream.client.await_stream(stream.key, 0)?;
 
-        Ok(Self {
-            id: stream.id,
-            krpc,
-            client,
-            phantom: PhantomData,
-        })
+        Ok(stream)
     }
 
     #[cfg(feature = "tokio")]
-    pub(crate) async fn new(
+    pub(crate) async fn new_async(
         client: Arc<Client>,
-        call: ProcedureCall,
+        call: Call<T>,
     ) -> Result<Self, RpcError> {
+        client.stream_status()?;
         let krpc = KRPC::new(client.clone());
-        let stream = krpc.add_stream(call, true).await?;
-        client.register_stream(stream.id);
-        client.await_stream(stream.id).await;
+        let add = krpc.add_stream(call).into_call()?;
+        let stream = loop {
+            let stream: schema::Stream =
+                CallBuilder::new(client.clone(), Ok(add.clone()), None)
+                    .call_async()
+                    .await?;
+            match Self::register(&client, stream.id, add.clone().into(), false)
+            {
+                Ok(stream) => break stream,
+                Err(removing) => {
+                    client.await_stream_removal_async(&removing).await?
+                }
+            }
+        };
+        stream.client.await_stream_async(stream.key, 0).await?;
 
+        Ok(stream)
+    }
+
+    /// Take ownership of a stream already added on the server
+    /// by the `add` call, unless it is being removed by a
+    /// handle dropped meanwhile, in which case it must be added
+    /// again once removed.
+    fn register(
+        client: &Arc<Client>,
+        id: u64,
+        add: ProcedureCall,
+        event: bool,
+    ) -> Result<Self, Removing> {
         Ok(Self {
-            id: stream.id,
-            krpc,
-            client,
+            key: client.register_stream(id, add, event)?,
+            krpc: KRPC::new(client.clone()),
+            client: client.clone(),
+            #[cfg(feature = "tokio")]
+            updates: None,
+            #[cfg(feature = "tokio")]
+            ended: false,
             phantom: PhantomData,
         })
     }
 
+    /// The server's id for this stream, which changes if the
+    /// client reconnects.
+    fn id(&self) -> Result<u64, RpcError> {
+        self.client.stream_id(self.key)
+    }
+
+    /// The sequence number of the latest result.
+    fn seq(&self) -> Result<u64, RpcError> {
+        self.client.stream_seq(self.key)
+    }
+
+    /// Decode an update's result, or return the error the
+    /// server reported with it.
+    fn decode(&self, result: &ProcedureResult) -> Result<T, RpcError> {
+        result.decode(self.client.clone())
+    }
+
+    /// Fail with the error the server reported with the latest
+    /// result, if any.
+    fn check(&self) -> Result<(), RpcError> {
+        self.last_error().map_or(Ok(()), Err)
+    }
+
     /// Set the update rate for this streaming procedure.
-    #[cfg(not(feature = "tokio"))]
     pub fn set_rate(&self, hz: f32) -> Result<(), RpcError> {
-        self.krpc.set_stream_rate(self.id, hz)
+        self.krpc.set_stream_rate(self.id()?, hz)?;
+        self.client.set_stream_rate(self.key, hz);
+        Ok(())
     }
 
-    /// Set the update rate for this streaming procedure.
+    /// Set the update rate for this streaming procedure
+    /// without blocking the current task.
     #[cfg(feature = "tokio")]
-    pub async fn set_rate(&self, hz: f32) -> Result<(), RpcError> {
-        self.krpc.set_stream_rate(self.id, hz).await
+    pub async fn set_rate_async(&self, hz: f32) -> Result<(), RpcError> {
+        CallBuilder::<()>::new(
+            self.client.clone(),
+            self.krpc.set_stream_rate_call(self.id()?, hz),
+            None,
+        )
+        .call_async()
+        .await?;
+        self.client.set_stream_rate(self.key, hz);
+        Ok(())
     }
 
     /// Retrieve the current result received for this
@@ -229,68 +835,469 @@ impl<T: RpcType + Send> Stream<T> {
     /// changed since the last call to [`get`][get]. Use
     /// [`wait`][wait] to block until the value has changed.
     ///
+    /// The result is already on hand, so this doesn't block,
+    /// and can be called from async code.
+    ///
+    /// Fails with [`RpcError::Server`] if the server reported
+    /// an error executing the procedure for the latest update,
+    /// e.g. because the vessel it refers to was destroyed, or
+    /// with [`RpcError::StreamClosed`] if the server failed to
+    /// add the stream again after the client reconnected.
+    ///
     /// [wait]: Stream::wait
     /// [get]: Stream::get
-    #[cfg(not(feature = "tokio"))]
     pub fn get(&self) -> Result<T, RpcError> {
-        self.client.read_stream(self.id)
+        self.get_with_seq().map(|(value, _)| value)
     }
 
-    /// Retrieve the current result received for this
-    /// procedure. This value is not guaranteed to have
-    /// changed since the last call to [`get`][get]. Use
-    /// [`wait`][wait] to block until the value has changed.
+    /// The error the server reported executing the procedure
+    /// for the latest update, if it failed. Unlike
+    /// [`get`][get], this doesn't decode the result.
     ///
-    /// [wait]: Stream::wait
     /// [get]: Stream::get
-    #[cfg(feature = "tokio")]
-    pub async fn get(&self) -> Result<T, RpcError> {
-        self.client.read_stream(self.id).await
+    pub fn last_error(&self) -> Option<RpcError> {
+        self.client.stream_error(self.key)
+    }
+
+    /// Retrieve the current result with its sequence number,
+    /// the number of updates received for this stream. Pass
+    /// the number to [`wait_for_update`][wait_for_update] to
+    /// wait for a newer result.
+    ///
+    /// [wait_for_update]: Stream::wait_for_update
+    pub fn get_with_seq(&self) -> Result<(T, u64), RpcError> {
+        self.client.read_stream(self.key)
     }
 
     /// Block the current thread of execution until this
     /// stream receives an update from the server.
-    #[cfg(not(feature = "tokio"))]
-    pub fn wait(&self) {
-        self.client.await_stream(self.id);
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the client
+    /// stops receiving stream updates, or with
+    /// [`RpcError::Server`] if the update reports an error.
+    pub fn wait(&self) -> Result<(), RpcError> {
+        self.wait_for_update(self.seq()?)?;
+        self.check()
     }
 
     /// Block the current thread of execution until this
     /// stream receives an update from the server or the
-    /// timeout is reached.
-    #[cfg(not(feature = "tokio"))]
-    pub fn wait_timeout(&self, dur: Duration) {
-        self.client.await_stream_timeout(self.id, dur);
+    /// timeout is reached. Returns whether an update was
+    /// received.
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the client
+    /// stops receiving stream updates, or with
+    /// [`RpcError::Server`] if the update reports an error.
+    pub fn wait_timeout(&self, dur: Duration) -> Result<bool, RpcError> {
+        let seq = self.seq()?;
+        if self.client.await_stream_timeout(self.key, seq, dur)? > seq {
+            self.check()?;
+            return Ok(true);
+        }
+        Ok(false)
     }
 
     /// Block the current thread of execution until this
-    /// stream receives an update from the server.
+    /// stream has a result newer than sequence number
+    /// `after`, returning the newer result's number. Returns
+    /// immediately if it already has one.
+    ///
+    /// The newer result may be an error reported by the
+    /// server, which [`get_with_seq`][get_with_seq] returns.
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the client
+    /// stops receiving stream updates first.
+    ///
+    /// [get_with_seq]: Stream::get_with_seq
+    pub fn wait_for_update(&self, after: u64) -> Result<u64, RpcError> {
+        self.client.await_stream(self.key, after)
+    }
+
+    /// Wait until this stream receives an update from the
+    /// server, without blocking the current task.
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the client
+    /// stops receiving stream updates, or with
+    /// [`RpcError::Server`] if the update reports an error.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self) -> Result<(), RpcError> {
+        self.wait_for_update_async(self.seq()?).await?;
+        self.check()
+    }
+
+    /// Wait until this stream has a result newer than
+    /// sequence number `after`, without blocking the current
+    /// task, returning the newer result's number.
+    ///
+    /// Fails with [`RpcError::StreamClosed`] if the client
+    /// stops receiving stream updates first.
     #[cfg(feature = "tokio")]
-    pub async fn wait(&self) {
-        self.client.await_stream(self.id).await;
+    pub async fn wait_for_update_async(
+        &self,
+        after: u64,
+    ) -> Result<u64, RpcError> {
+        self.client.await_stream_async(self.key, after).await
+    }
+
+    /// Call `callback` with the result of every update
+    /// received from now on, until the returned guard is
+    /// dropped.
+    ///
+    /// Callbacks are called on the thread receiving stream
+    /// updates, after each update has been decoded and stored.
+    /// So for each stream, callbacks are called one at a time,
+    /// in the order updates are received, and [`get`][get]
+    /// returns the same value from within a callback. Updates
+    /// that fail to decode or report an error are skipped, see
+    /// [`last_error`][last_error].
+    ///
+    /// Callbacks hold up the thread receiving updates, so they
+    /// should return quickly. They may read and drop streams,
+    /// and register or drop callbacks, but mustn't wait for
+    /// stream updates or call procedures, which may never
+    /// complete. Streams dropped there are removed from the
+    /// server in the background.
+    /// A callback may be called once more if its guard is
+    /// dropped while an update is delivered, and a callback
+    /// that panics isn't called again.
+    ///
+    /// ```no_run
+    /// # use krpc_client::{error::RpcError, stream::Stream};
+    /// # fn run(ut: Stream<f64>) -> Result<(), RpcError> {
+    /// let _guard = ut.on_update(|ut| println!("It's {ut} o'clock"))?;
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// [get]: Stream::get
+    /// [last_error]: Stream::last_error
+    pub fn on_update<F>(
+        &self,
+        mut callback: F,
+    ) -> Result<CallbackGuard, RpcError>
+    where
+        F: FnMut(T) + Send + 'static,
+        T: 'static,
+    {
+        // Don't keep the client alive from its own stream.
+        let client = Arc::downgrade(&self.client);
+        let callback = move |result: &ProcedureResult| {
+            let Some(client) = client.upgrade() else {
+                return;
+            };
+            if let Ok(value) = result.decode(client) {
+                callback(value);
+            }
+        };
+        self.client
+            .add_stream_callback(self.key, Arc::new(Mutex::new(callback)))
+    }
+
+    /// Iterate over this stream's updates, blocking the
+    /// current thread of execution until each arrives.
+    ///
+    /// The iterator yields exactly one result for each update
+    /// received after it was created, in order, however long
+    /// the caller takes between them, including errors the
+    /// server reports with an update. Once the client stops
+    /// receiving stream updates, it yields
+    /// [`RpcError::StreamClosed`] and ends.
+    ///
+    /// ```no_run
+    /// # use krpc_client::{error::RpcError, stream::Stream};
+    /// # fn run(ut: Stream<f64>) -> Result<(), RpcError> {
+    /// for ut in ut.updates().take(10) {
+    ///     println!("It's {} o'clock", ut?);
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn updates(&self) -> Updates<'_, T> {
+        let (tx, receiver) = mpsc::channel();
+        self.client.listen_stream(self.key, Listener::Blocking(tx));
+        Updates {
+            stream: self,
+            receiver,
+            ended: false,
+        }
     }
 }
 
-impl<T: RpcType + Send> Drop for Stream<T> {
-    // Try to remove the stream if it's dropped, but don't panic
-    // if unable.
-    #[cfg(not(feature = "tokio"))]
+impl<T: RpcType + Send> Iterator for Updates<'_, T> {
+    type Item = Result<T, RpcError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.ended {
+            return None;
+        }
+        match self.receiver.recv() {
+            Ok(result) => Some(self.stream.decode(&result)),
+            Err(_) => {
+                self.ended = true;
+                self.stream
+                    .client
+                    .check_stream(self.stream.key)
+                    .err()
+                    .map(Err)
+            }
+        }
+    }
+}
+
+impl Drop for CallbackGuard {
     fn drop(&mut self) {
-        self.krpc.remove_stream(self.id).ok();
-        self.client.remove_stream(self.id).ok();
+        if let Some(entry) = self.entry.upgrade() {
+            let mut latest = entry.latest.lock().unwrap();
+            latest.callbacks.retain(|(id, _)| *id != self.id);
+        }
+    }
+}
+
+/// Yields exactly one result for each update received after
+/// the stream is first polled, in order, including errors the
+/// server reports with an update. Once the client stops
+/// receiving stream updates, yields [`RpcError::StreamClosed`]
+/// and ends.
+#[cfg(feature = "tokio")]
+impl<T: RpcType + Send + Unpin> futures_core::Stream for Stream<T> {
+    type Item = Result<T, RpcError>;
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let this = self.get_mut();
+        if this.ended {
+            return Poll::Ready(None);
+        }
+        let updates = this.updates.get_or_insert_with(|| {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            this.client.listen_stream(this.key, Listener::Async(tx));
+            rx
+        });
+        match updates.poll_recv(cx) {
+            Poll::Ready(Some(result)) => {
+                Poll::Ready(Some(this.decode(&result)))
+            }
+            Poll::Ready(None) => {
+                this.ended = true;
+                Poll::Ready(this.client.check_stream(this.key).err().map(Err))
+            }
+            Poll::Pending => Poll::Pending,
+        }
+    }
+}
+
+impl Event {
+    /// Add an event to the server that occurs when
+    /// `expression` evaluates to `true`, and start it.
+    pub fn new(
+        client: Arc<Client>,
+        expression: &Expression,
+    ) -> Result<Self, RpcError> {
+        client.stream_status()?;
+        let krpc = KRPC::new(client.clone());
+        let add = krpc.add_event_call(expression)?;
+        let stream = loop {
+            let event: schema::Event =
+                CallBuilder::new(client.clone(), Ok(add.clone()), None)
+                    .call()?;
+            match Stream::register(
+                &client,
+                event.stream.id,
+                add.clone().into(),
+                true,
+            ) {
+                Ok(stream) => break stream,
+                Err(removing) => client.await_stream_removal(&removing)?,
+            }
+        };
+        krpc.start_stream(stream.id()?)?;
+
+        Ok(Self { stream })
     }
 
+    /// Add an event to the server that occurs when
+    /// `expression` evaluates to `true`, and start it without
+    /// blocking the current task.
     #[cfg(feature = "tokio")]
+    pub async fn new_async(
+        client: Arc<Client>,
+        expression: &crate::services_async::krpc::Expression,
+    ) -> Result<Self, RpcError> {
+        client.stream_status()?;
+        let krpc = crate::services_async::krpc::KRPC::new(client.clone());
+        let add = krpc.add_event_call(expression)?;
+        let stream = loop {
+            let event: schema::Event =
+                CallBuilder::new(client.clone(), Ok(add.clone()), None)
+                    .call_async()
+                    .await?;
+            match Stream::register(
+                &client,
+                event.stream.id,
+                add.clone().into(),
+                true,
+            ) {
+                Ok(stream) => break stream,
+                Err(removing) => {
+                    client.await_stream_removal_async(&removing).await?
+                }
+            }
+        };
+        krpc.start_stream(stream.id()?).await?;
+
+        Ok(Self { stream })
+    }
+
+    /// Build `expr` on the server, and add an event that
+    /// occurs when it evaluates to `true`.
+    pub fn from_expr(
+        client: Arc<Client>,
+        expr: &Expr<bool>,
+    ) -> Result<Self, RpcError> {
+        let expression = expr.build(&client)?;
+        Self::new(client, &expression)
+    }
+
+    /// Build `expr` on the server, and add an event that
+    /// occurs when it evaluates to `true`, without blocking
+    /// the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn from_expr_async(
+        client: Arc<Client>,
+        expr: &Expr<bool>,
+    ) -> Result<Self, RpcError> {
+        let expression = expr.build_async(&client).await?;
+        Self::new_async(client, &expression).await
+    }
+
+    /// Set the rate at which the server checks the event's
+    /// expression.
+    pub fn set_rate(&self, hz: f32) -> Result<(), RpcError> {
+        self.stream.set_rate(hz)
+    }
+
+    /// Set the rate at which the server checks the event's
+    /// expression, without blocking the current task.
+    #[cfg(feature = "tokio")]
+    pub async fn set_rate_async(&self, hz: f32) -> Result<(), RpcError> {
+        self.stream.set_rate_async(hz).await
+    }
+
+    /// Block the current thread of execution until the event
+    /// occurs. Returns immediately if it already has.
+    ///
+    /// Fails with [`RpcError::Server`] if the server reports an
+    /// error evaluating the event's expression.
+    pub fn wait(&self) -> Result<(), RpcError> {
+        loop {
+            // Read the sequence number first, so an update after
+            // reading the result isn't missed.
+            let seq = self.stream.seq()?;
+            if self.occurred(seq)? {
+                return Ok(());
+            }
+            self.stream.wait_for_update(seq)?;
+        }
+    }
+
+    /// Block the current thread of execution until the event
+    /// occurs or the timeout is reached. Returns whether the
+    /// event occurred.
+    pub fn wait_timeout(&self, dur: Duration) -> Result<bool, RpcError> {
+        let deadline = Instant::now() + dur;
+        loop {
+            let seq = self.stream.seq()?;
+            if self.occurred(seq)? {
+                return Ok(true);
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(false);
+            }
+            self.stream.client.await_stream_timeout(
+                self.stream.key,
+                seq,
+                deadline - now,
+            )?;
+        }
+    }
+
+    /// Wait until the event occurs, without blocking the
+    /// current task. Returns immediately if it already has.
+    #[cfg(feature = "tokio")]
+    pub async fn wait_async(&self) -> Result<(), RpcError> {
+        loop {
+            let seq = self.stream.seq()?;
+            if self.occurred(seq)? {
+                return Ok(());
+            }
+            self.stream.wait_for_update_async(seq).await?;
+        }
+    }
+
+    /// Whether the event has occurred, given `seq` updates
+    /// received so far.
+    fn occurred(&self, seq: u64) -> Result<bool, RpcError> {
+        // The stream has no value until its first update.
+        if seq == 0 {
+            return Ok(false);
+        }
+        self.stream.get()
+    }
+}
+
+impl<T: RpcType + Send> Clone for Stream<T> {
+    /// Take another handle on the same stream. A clone polled
+    /// as a [`futures_core::Stream`] receives its own copy of
+    /// each update.
+    fn clone(&self) -> Self {
+        self.client.retain_stream(self.key);
+        Self {
+            key: self.key,
+            krpc: self.krpc.clone(),
+            client: self.client.clone(),
+            #[cfg(feature = "tokio")]
+            updates: None,
+            #[cfg(feature = "tokio")]
+            ended: false,
+            phantom: PhantomData,
+        }
+    }
+}
+
+impl<T: RpcType + Send> Drop for Stream<T> {
+    // Try to remove the stream once no handle holds it, but
+    // don't panic if unable.
     fn drop(&mut self) {
-        let krpc = self.krpc.clone();
+        if !self.client.release_stream(self.key) {
+            return;
+        }
+        let key = self.key;
         let client = self.client.clone();
-        let id = self.id;
-        let refcount = client.release_stream(id);
-        if refcount == 0 {
-            tokio::task::spawn(async move {
-                krpc.remove_stream(id).await.ok();
-                client.remove_stream(id).await.ok();
-            });
+        let krpc = self.krpc.clone();
+        let remove = move || {
+            // The stream is gone if the client reconnected in the
+            // meantime.
+            if let Ok(id) = client.stream_id(key) {
+                krpc.remove_stream(id).ok();
+            }
+            client.forget_stream(key);
+        };
+
+        // Removing the stream waits for the server, which would
+        // hold up a runtime's worker thread, and never finish on
+        // a reader's thread, e.g. if dropped in a callback.
+        if client::is_reader_thread() {
+            thread::spawn(remove);
+            return;
+        }
+        #[cfg(feature = "tokio")]
+        if let Ok(runtime) = tokio::runtime::Handle::try_current() {
+            runtime.spawn_blocking(remove);
+            return;
         }
+        remove();
     }
 }
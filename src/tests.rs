@@ -0,0 +1,714 @@
+//! Clients connected to stand-in servers, speaking just enough
+//! of each protocol to answer the requests made.
+
+use std::io::Write;
+
+use protobuf::Message;
+
+use crate::schema;
+
+/// The client identifier given out by the stand-in servers.
+const CLIENT_ID: &[u8] = b"0123456789abcdef";
+
+/// The id of the stream the stand-in servers add.
+const STREAM_ID: u64 = 1;
+
+/// The response to `request`, answering GetClientID with
+/// [`CLIENT_ID`] and AddStream with [`STREAM_ID`].
+fn respond(request: &schema::Request) -> schema::Response {
+    let results = request
+        .calls
+        .iter()
+        .map(|call| {
+            let value = match call.procedure.as_str() {
+                "GetClientID" => bytes_value(CLIENT_ID),
+                "AddStream" => schema::Stream {
+                    id: STREAM_ID,
+                    ..Default::default()
+                }
+                .write_to_bytes()
+                .unwrap(),
+                "RemoveStream" => Vec::new(),
+                procedure => panic!("unexpected call to {procedure}"),
+            };
+            schema::ProcedureResult {
+                value,
+                ..Default::default()
+            }
+        })
+        .collect();
+
+    schema::Response {
+        results,
+        ..Default::default()
+    }
+}
+
+/// An update of the stream the stand-in servers add.
+fn update(value: &[u8]) -> schema::StreamUpdate {
+    schema::StreamUpdate {
+        results: vec![schema::StreamResult {
+            id: STREAM_ID,
+            result: Some(schema::ProcedureResult {
+                value: bytes_value(value),
+                ..Default::default()
+            })
+            .into(),
+            ..Default::default()
+        }],
+        ..Default::default()
+    }
+}
+
+/// Write a length-delimited message, as the TCP and SerialIO
+/// servers do.
+fn write_message(conn: &mut impl Write, message: &impl Message) {
+    message.write_length_delimited_to_writer(conn).unwrap();
+    conn.flush().unwrap();
+}
+
+fn bytes_value(bytes: &[u8]) -> Vec<u8> {
+    let mut value = Vec::new();
+    let mut output = protobuf::CodedOutputStream::vec(&mut value);
+    output.write_bytes_no_tag(bytes).unwrap();
+    output.flush().unwrap();
+    drop(output);
+    value
+}
+
+#[cfg(feature = "websocket")]
+mod websocket {
+    use std::{
+        net::{TcpListener, TcpStream},
+        sync::mpsc::{self, TryRecvError},
+        thread,
+        time::Duration,
+    };
+
+    use protobuf::Message;
+    use tungstenite::{
+        handshake::server::{Request, Response},
+        WebSocket,
+    };
+
+    use super::*;
+    use crate::{client::Protocol, services::krpc::KRPC, Client};
+
+    /// Accept a WebSocket connection, returning the query of
+    /// the URL connected to.
+    #[allow(clippy::result_large_err)]
+    fn accept(listener: &TcpListener) -> (WebSocket<TcpStream>, String) {
+        let (conn, _) = listener.accept().unwrap();
+        let mut query = String::new();
+        let ws =
+            tungstenite::accept_hdr(conn, |request: &Request, response| {
+                query = request.uri().query().unwrap_or_default().to_owned();
+                Ok::<Response, _>(response)
+            })
+            .unwrap();
+        (ws, query)
+    }
+
+    /// Receive the next request, keeping the pongs received
+    /// before it.
+    fn recv(
+        ws: &mut WebSocket<TcpStream>,
+        pongs: &mut Vec<Vec<u8>>,
+    ) -> Option<schema::Request> {
+        match ws.read().unwrap() {
+            tungstenite::Message::Binary(message) => {
+                Some(schema::Request::parse_from_bytes(&message).unwrap())
+            }
+            tungstenite::Message::Pong(payload) => {
+                pongs.push(payload);
+                None
+            }
+            message => panic!("unexpected message {message:?}"),
+        }
+    }
+
+    fn send(ws: &mut WebSocket<TcpStream>, message: &impl Message) {
+        let message = message.write_to_bytes().unwrap();
+        ws.send(tungstenite::Message::binary(message)).unwrap();
+    }
+
+    #[test]
+    fn stream_update() {
+        let rpc = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpListener::bind("127.0.0.1:0").unwrap();
+        let rpc_port = rpc.local_addr().unwrap().port();
+        let stream_port = stream.local_addr().unwrap().port();
+        let (done, closed) = mpsc::channel::<()>();
+
+        let server = thread::spawn(move || {
+            let mut pongs = Vec::new();
+            let (mut rpc, query) = accept(&rpc);
+            assert_eq!(query, "name=test%20client");
+            let request = recv(&mut rpc, &mut pongs).unwrap();
+            assert_eq!(request.calls[0].procedure, "GetClientID");
+            send(&mut rpc, &respond(&request));
+
+            let (mut stream, query) = accept(&stream);
+            assert_eq!(query, "id=MDEyMzQ1Njc4OWFiY2RlZg%3D%3D");
+
+            // The reader answers while requests are sent.
+            rpc.send(tungstenite::Message::Ping(b"ping".to_vec()))
+                .unwrap();
+            let request = loop {
+                if let Some(request) = recv(&mut rpc, &mut pongs) {
+                    break request;
+                }
+            };
+            assert_eq!(request.calls[0].procedure, "AddStream");
+            send(&mut rpc, &respond(&request));
+            while pongs.is_empty() {
+                assert!(recv(&mut rpc, &mut pongs).is_none());
+            }
+            assert_eq!(pongs, [b"ping"]);
+
+            // Keep updating the stream until the client is done,
+            // as hanging up would close its streams.
+            while let Err(TryRecvError::Empty) = closed.try_recv() {
+                send(&mut stream, &update(b"update"));
+                thread::sleep(Duration::from_millis(10));
+            }
+        });
+
+        let client = Client::builder()
+            .name("test client")
+            .rpc_port(rpc_port)
+            .stream_port(stream_port)
+            .protocol(Protocol::WebSocket)
+            .connect()
+            .unwrap();
+        let id = KRPC::new(client.clone()).get_client_id_stream().unwrap();
+        assert_eq!(id.get().unwrap(), b"update");
+
+        drop(done);
+        server.join().unwrap();
+    }
+}
+
+#[cfg(all(feature = "serial", unix))]
+mod serial {
+    use std::{
+        io::Read,
+        sync::{
+            mpsc::{self, TryRecvError},
+            Arc,
+        },
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use protobuf::CodedInputStream;
+    use serialport::{SerialPort, TTYPort};
+
+    use super::*;
+    use crate::{client::Protocol, services::krpc::KRPC, Client};
+
+    /// Serve a client on `port` until `closed` is dropped.
+    fn serve(mut port: TTYPort, closed: mpsc::Receiver<()>) -> JoinHandle<()> {
+        port.set_timeout(Duration::from_secs(10)).unwrap();
+        let mut reader = port.try_clone_native().unwrap();
+
+        thread::spawn(move || {
+            let mut input = CodedInputStream::new(&mut reader as &mut dyn Read);
+            let request: schema::MultiplexedRequest =
+                input.read_message().unwrap();
+            assert_eq!(request.connection_request.client_name, "test client");
+            write_message(
+                &mut port,
+                &schema::ConnectionResponse {
+                    client_identifier: CLIENT_ID.to_vec(),
+                    ..Default::default()
+                },
+            );
+
+            let request: schema::MultiplexedRequest =
+                input.read_message().unwrap();
+            assert_eq!(request.request.calls[0].procedure, "AddStream");
+            write_message(
+                &mut port,
+                &schema::MultiplexedResponse {
+                    response: Some(respond(&request.request)).into(),
+                    ..Default::default()
+                },
+            );
+
+            // Keep updating the stream until the client is done,
+            // as hanging up would close its streams.
+            while let Err(TryRecvError::Empty) = closed.try_recv() {
+                write_message(
+                    &mut port,
+                    &schema::MultiplexedResponse {
+                        stream_update: Some(update(b"update")).into(),
+                        ..Default::default()
+                    },
+                );
+                thread::sleep(Duration::from_millis(10));
+            }
+        })
+    }
+
+    fn check(client: Arc<Client>) {
+        let id = KRPC::new(client.clone()).get_client_id_stream().unwrap();
+        assert_eq!(id.get().unwrap(), b"update");
+        // The reader stops polling the device once closed.
+        client.close();
+    }
+
+    #[test]
+    fn stream_update() {
+        let (port, device) = TTYPort::pair().unwrap();
+        let (done, closed) = mpsc::channel();
+        let server = serve(port, closed);
+
+        let client = Client::builder()
+            .name("test client")
+            .protocol(Protocol::SerialIO)
+            .serial_port(device.name().unwrap())
+            .connect()
+            .unwrap();
+        check(client);
+
+        drop(done);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn connect_io() {
+        let (port, device) = TTYPort::pair().unwrap();
+        let (done, closed) = mpsc::channel();
+        let server = serve(port, closed);
+
+        let reader = device.try_clone_native().unwrap();
+        let client = Client::builder()
+            .name("test client")
+            .connect_io(reader, device)
+            .unwrap();
+        check(client);
+
+        drop(done);
+        server.join().unwrap();
+    }
+}
+
+mod multiplexed {
+    use std::{
+        io::Read,
+        net::{TcpListener, TcpStream},
+        sync::{mpsc, Arc, Mutex},
+        thread::{self, JoinHandle},
+        time::Duration,
+    };
+
+    use protobuf::CodedInputStream;
+
+    use super::*;
+    use crate::{services::krpc::KRPC, Client};
+
+    /// A stand-in server answering every request, which sends
+    /// an update of the stream it adds when added, and when
+    /// told to.
+    pub(super) struct Server {
+        conn: Arc<Mutex<TcpStream>>,
+        /// The procedures called, in order.
+        calls: mpsc::Receiver<String>,
+        thread: JoinHandle<()>,
+    }
+
+    impl Server {
+        /// Send an update of the stream the server adds.
+        pub(super) fn update(&self, value: &[u8]) {
+            let mut conn = self.conn.lock().unwrap();
+            write_message(&mut *conn, &stream_update(value));
+        }
+
+        /// Wait for the client to call `procedure`.
+        pub(super) fn wait_for(&self, procedure: &str) {
+            let timeout = Duration::from_secs(10);
+            while self.calls.recv_timeout(timeout).unwrap() != procedure {}
+        }
+
+        /// Wait for the client to hang up.
+        pub(super) fn join(self) {
+            self.thread.join().unwrap();
+        }
+    }
+
+    /// Connect a client to a [`Server`].
+    pub(super) fn connect() -> (Arc<Client>, Server) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let accepted = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = conn.try_clone().unwrap();
+            let mut input = CodedInputStream::new(&mut reader as &mut dyn Read);
+            let _: schema::MultiplexedRequest = input.read_message().unwrap();
+            write_message(
+                &mut conn,
+                &schema::ConnectionResponse {
+                    client_identifier: CLIENT_ID.to_vec(),
+                    ..Default::default()
+                },
+            );
+            conn
+        });
+
+        let client = Client::builder()
+            .rpc_port(port)
+            .multiplexed(true)
+            .connect()
+            .unwrap();
+        let conn = accepted.join().unwrap();
+
+        let mut reader = conn.try_clone().unwrap();
+        let conn = Arc::new(Mutex::new(conn));
+        let writer = conn.clone();
+        let (called, calls) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut input = CodedInputStream::new(&mut reader as &mut dyn Read);
+            while let Ok(request) =
+                input.read_message::<schema::MultiplexedRequest>()
+            {
+                let mut message = response(respond(&request.request));
+                let procedure = &request.request.calls[0].procedure;
+                if procedure == "AddStream" {
+                    message.stream_update = Some(update(b"added")).into();
+                }
+                write_message(&mut *writer.lock().unwrap(), &message);
+                called.send(procedure.clone()).ok();
+            }
+        });
+
+        (
+            client,
+            Server {
+                conn,
+                calls,
+                thread,
+            },
+        )
+    }
+
+    fn response(response: schema::Response) -> schema::MultiplexedResponse {
+        schema::MultiplexedResponse {
+            response: Some(response).into(),
+            ..Default::default()
+        }
+    }
+
+    fn stream_update(value: &[u8]) -> schema::MultiplexedResponse {
+        schema::MultiplexedResponse {
+            stream_update: Some(update(value)).into(),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn interleaved() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = thread::spawn(move || {
+            let (mut conn, _) = listener.accept().unwrap();
+            let mut reader = conn.try_clone().unwrap();
+            let mut input = CodedInputStream::new(&mut reader as &mut dyn Read);
+            let request: schema::MultiplexedRequest =
+                input.read_message().unwrap();
+            assert_eq!(request.connection_request.client_name, "test client");
+            write_message(
+                &mut conn,
+                &schema::ConnectionResponse {
+                    client_identifier: CLIENT_ID.to_vec(),
+                    ..Default::default()
+                },
+            );
+
+            // Updates arrive before a response, and along with one.
+            let request: schema::MultiplexedRequest =
+                input.read_message().unwrap();
+            assert_eq!(request.request.calls[0].procedure, "AddStream");
+            write_message(&mut conn, &stream_update(b"first"));
+            let mut message = response(respond(&request.request));
+            message.stream_update = Some(update(b"second")).into();
+            write_message(&mut conn, &message);
+
+            let request: schema::MultiplexedRequest =
+                input.read_message().unwrap();
+            assert_eq!(request.request.calls[0].procedure, "GetClientID");
+            write_message(&mut conn, &stream_update(b"third"));
+            write_message(&mut conn, &response(respond(&request.request)));
+
+            // Answer the stream's removal until the client hangs
+            // up, so it doesn't see the connection close first.
+            while let Ok(request) =
+                input.read_message::<schema::MultiplexedRequest>()
+            {
+                write_message(&mut conn, &response(respond(&request.request)));
+            }
+        });
+
+        let client = Client::builder()
+            .name("test client")
+            .rpc_port(port)
+            .multiplexed(true)
+            .connect()
+            .unwrap();
+        let krpc = KRPC::new(client.clone());
+        let stream = krpc.get_client_id_stream().unwrap();
+        assert_eq!(krpc.get_client_id().unwrap(), CLIENT_ID);
+        // Updates received before a response are applied first.
+        assert_eq!(stream.get().unwrap(), b"third");
+
+        drop(stream);
+        client.close();
+        server.join().unwrap();
+    }
+}
+
+mod batch {
+    use super::*;
+    use crate::{error::RpcError, services::krpc::KRPC};
+
+    #[test]
+    fn call_from_another_batch() {
+        let (client, server) = multiplexed::connect();
+        let krpc = KRPC::new(client.clone());
+
+        let mut first = client.batch();
+        let call = first.add(krpc.get_client_id_call().unwrap());
+        let mut second = client.batch();
+        second.add(krpc.get_client_id_call().unwrap());
+
+        let results = second.execute().unwrap();
+        assert!(matches!(results.get(call), Err(RpcError::Encoding(_))));
+        let results = first.execute().unwrap();
+        assert_eq!(results.get(call).unwrap(), CLIENT_ID);
+
+        client.close();
+        server.join();
+    }
+}
+
+mod rpc_enum {
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::{
+        schema::{DecodeUntagged, EncodeUntagged},
+        services::space_center::{EditorFacility, MapFilterType},
+        Client,
+    };
+
+    fn round_trip<T: DecodeUntagged + EncodeUntagged>(
+        client: &Arc<Client>,
+        value: T,
+    ) -> T {
+        T::decode_untagged(client.clone(), &value.encode_untagged().unwrap())
+            .unwrap()
+    }
+
+    #[test]
+    fn wire_values() {
+        let (client, server) = multiplexed::connect();
+
+        // Enum values are zigzag encoded, like sint32.
+        assert_eq!(MapFilterType::All.encode_untagged().unwrap(), [0x01]);
+        assert_eq!(round_trip(&client, MapFilterType::All), MapFilterType::All);
+        assert_eq!(MapFilterType::DeployedScienceController.value(), 16384);
+        assert_eq!(
+            MapFilterType::from_value(16384),
+            MapFilterType::DeployedScienceController
+        );
+
+        let facilities = [
+            EditorFacility::VAB,
+            EditorFacility::SPH,
+            EditorFacility::None,
+        ];
+        assert_eq!(facilities.map(|facility| facility.value()), [1, 2, 0]);
+        for facility in facilities {
+            assert_eq!(round_trip(&client, facility), facility);
+        }
+
+        // Values unknown to the client are kept as they are.
+        let unknown =
+            EditorFacility::decode_untagged(client.clone(), &bytes_of(7))
+                .unwrap();
+        assert_eq!(unknown, EditorFacility::Unrecognized(7));
+        assert_eq!(unknown.encode_untagged().unwrap(), bytes_of(7));
+
+        client.close();
+        server.join();
+    }
+
+    fn bytes_of(value: i32) -> Vec<u8> {
+        value.encode_untagged().unwrap()
+    }
+}
+
+mod dynamic {
+    use super::*;
+    use crate::{
+        dynamic::{decode_value, encode_value, Value},
+        error::RpcError,
+        schema::{type_::TypeCode, Tuple, Type},
+    };
+
+    fn ty(code: TypeCode, types: Vec<Type>) -> Type {
+        Type {
+            code: code.into(),
+            types,
+            ..Default::default()
+        }
+    }
+
+    fn scalar(code: TypeCode) -> Type {
+        ty(code, Vec::new())
+    }
+
+    fn round_trip(ty: &Type, value: Value) {
+        let encoded = encode_value(ty, &value).unwrap();
+        assert_eq!(decode_value(ty, &encoded).unwrap(), value);
+    }
+
+    #[test]
+    fn scalars() {
+        round_trip(&scalar(TypeCode::DOUBLE), Value::Double(-1.5));
+        round_trip(&scalar(TypeCode::FLOAT), Value::Float(2.25));
+        round_trip(&scalar(TypeCode::SINT32), Value::SInt32(-7));
+        round_trip(&scalar(TypeCode::SINT64), Value::SInt64(i64::MIN));
+        round_trip(&scalar(TypeCode::UINT32), Value::UInt32(u32::MAX));
+        round_trip(&scalar(TypeCode::UINT64), Value::UInt64(u64::MAX));
+        round_trip(&scalar(TypeCode::BOOL), Value::Bool(true));
+        round_trip(&scalar(TypeCode::STRING), Value::String("kerbin".into()));
+        round_trip(&scalar(TypeCode::BYTES), Value::Bytes(vec![0, 1, 2]));
+        round_trip(&scalar(TypeCode::ENUMERATION), Value::Enum(-1));
+        round_trip(&scalar(TypeCode::CLASS), Value::Object(42));
+        // Null class handles are sent as id 0.
+        round_trip(&scalar(TypeCode::CLASS), Value::None);
+    }
+
+    #[test]
+    fn collections() {
+        let class = scalar(TypeCode::CLASS);
+        let string = scalar(TypeCode::STRING);
+
+        round_trip(
+            &ty(TypeCode::LIST, vec![class.clone()]),
+            Value::List(vec![Value::Object(1), Value::None]),
+        );
+        round_trip(
+            &ty(TypeCode::SET, vec![string.clone()]),
+            Value::Set(vec![Value::String("a".into())]),
+        );
+        round_trip(
+            &ty(TypeCode::DICTIONARY, vec![string.clone(), class.clone()]),
+            Value::Dictionary(vec![
+                (Value::String("a".into()), Value::Object(1)),
+                (Value::String("b".into()), Value::None),
+            ]),
+        );
+        round_trip(
+            &ty(TypeCode::TUPLE, vec![string, class]),
+            Value::Tuple(vec![Value::String("a".into()), Value::None]),
+        );
+    }
+
+    #[test]
+    fn tuple_length() {
+        let pair = ty(
+            TypeCode::TUPLE,
+            vec![scalar(TypeCode::BOOL), scalar(TypeCode::BOOL)],
+        );
+        assert!(matches!(
+            encode_value(&pair, &Value::Tuple(vec![Value::Bool(true)])),
+            Err(RpcError::Encoding(_))
+        ));
+
+        let item = encode_value(&scalar(TypeCode::BOOL), &Value::Bool(true));
+        let triple = Tuple {
+            items: vec![item.unwrap(); 3],
+            ..Default::default()
+        };
+        assert!(matches!(
+            decode_value(&pair, &triple.write_to_bytes().unwrap()),
+            Err(RpcError::Encoding(_))
+        ));
+    }
+}
+
+mod env {
+    use std::{env::VarError, ffi::OsString};
+
+    use crate::client::parse_port;
+
+    #[test]
+    fn ports() {
+        assert_eq!(parse_port("PORT", Ok("50002".into()), 50000), Ok(50002));
+        assert_eq!(
+            parse_port("PORT", Err(VarError::NotPresent), 50000),
+            Ok(50000)
+        );
+
+        for value in ["", "port", "-1", "65536"] {
+            assert_eq!(
+                parse_port("PORT", Ok(value.into()), 50000),
+                Err(format!("PORT is not a port number: {value:?}"))
+            );
+        }
+        let value = OsString::from("port");
+        assert!(parse_port("PORT", Err(VarError::NotUnicode(value)), 50000)
+            .is_err());
+    }
+}
+
+mod stream {
+    use std::{sync::mpsc, time::Duration};
+
+    use super::*;
+    use crate::services::krpc::KRPC;
+
+    #[test]
+    fn wait_timeout() {
+        let (client, server) = multiplexed::connect();
+        let stream = KRPC::new(client.clone()).get_client_id_stream().unwrap();
+        assert_eq!(stream.get().unwrap(), b"added");
+
+        assert!(!stream.wait_timeout(Duration::from_millis(50)).unwrap());
+        server.update(b"updated");
+        assert!(stream.wait_timeout(Duration::from_secs(10)).unwrap());
+        assert_eq!(stream.get().unwrap(), b"updated");
+
+        drop(stream);
+        client.close();
+        server.join();
+    }
+
+    #[test]
+    fn drop_in_callback() {
+        let (client, server) = multiplexed::connect();
+        let stream = KRPC::new(client.clone()).get_client_id_stream().unwrap();
+
+        // The callback holds the last handle, and drops it on
+        // the thread that would receive the removal's response.
+        let mut held = Some(stream.clone());
+        let (dropped, done) = mpsc::channel();
+        let _guard = stream
+            .on_update(move |_| {
+                held.take();
+                dropped.send(()).ok();
+            })
+            .unwrap();
+        drop(stream);
+
+        server.update(b"updated");
+        done.recv_timeout(Duration::from_secs(10)).unwrap();
+        server.wait_for("RemoveStream");
+
+        client.close();
+        server.join();
+    }
+}
@@ -0,0 +1,763 @@
+use std::{
+    collections::VecDeque,
+    io::{BufRead, BufReader, Read, Write},
+    net::{Shutdown, TcpStream},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use protobuf::Message;
+#[cfg(feature = "tokio")]
+use tokio::sync::oneshot;
+
+use crate::{
+    error::RpcError,
+    schema::{self, ConnectionRequest, ConnectionResponse, StreamUpdate},
+};
+
+/// A connection to the server, carrying protobuf messages.
+///
+/// Once connected, it is split into a [`Writer`] for requests
+/// and [`Reader`]s for responses and stream updates.
+pub(crate) enum Transport {
+    /// Length-delimited messages over TCP.
+    Tcp(TcpStream),
+    /// A message per binary frame over a WebSocket.
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<tungstenite::WebSocket<SharedSocket>>),
+    /// `MultiplexedRequest` and `MultiplexedResponse` messages
+    /// over a device carrying both procedure calls and stream
+    /// updates.
+    Multiplexed(Device),
+}
+
+/// A device shared by procedure calls and stream updates.
+pub(crate) enum Device {
+    Tcp(TcpStream),
+    /// The halves of a device such as a serial port.
+    #[cfg(feature = "serial")]
+    Io {
+        reader: Polled<Box<dyn Read + Send>>,
+        writer: Polled<Box<dyn Write + Send>>,
+    },
+}
+
+/// The sending half of an RPC connection.
+///
+/// Requests are queued to a thread writing them to the
+/// connection, so sending never blocks. The server answers
+/// requests in the order they are sent, so its [`Reader`]
+/// passes each response on to the oldest caller still
+/// waiting, and requests can be sent before earlier ones are
+/// answered.
+pub(crate) struct Writer {
+    requests: mpsc::Sender<(schema::Request, Waiter)>,
+}
+
+/// The receiving half of a connection, which receives stream
+/// updates and passes responses on to callers waiting on the
+/// [`Writer`].
+pub(crate) struct Reader {
+    half: ReadHalf,
+    messages: Messages,
+    /// Callers waiting for responses, if the connection
+    /// carries them.
+    pending: Option<Arc<Pending>>,
+    /// Stops the reader from another thread.
+    closer: Closer,
+}
+
+/// The messages received by a [`Reader`].
+enum Messages {
+    Responses,
+    Updates,
+    /// `MultiplexedResponse` messages, carrying either.
+    Multiplexed,
+}
+
+enum WriteHalf {
+    Io(Box<dyn Write + Send>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<tungstenite::WebSocket<SharedSocket>>),
+}
+
+enum ReadHalf {
+    /// Buffered for as long as the [`Reader`] lives, so data
+    /// read ahead is kept for the next message.
+    Io(BufReader<Box<dyn Read + Send>>),
+    #[cfg(feature = "websocket")]
+    WebSocket(Box<tungstenite::WebSocket<SharedSocket>>),
+}
+
+/// A socket shared by both halves of a WebSocket connection,
+/// as the reader answers pings. Frames are written whole when
+/// flushed, so they can't interleave with the other half's.
+#[cfg(feature = "websocket")]
+pub(crate) struct SharedSocket {
+    conn: TcpStream,
+    /// Written since the last flush.
+    buffer: Vec<u8>,
+    /// Held while writing to `conn`.
+    writing: Arc<Mutex<()>>,
+}
+
+/// A caller waiting for a response, on a thread or in a task.
+enum Waiter {
+    Blocking(mpsc::Sender<schema::Response>),
+    #[cfg(feature = "tokio")]
+    Async(oneshot::Sender<schema::Response>),
+}
+
+/// Callers waiting for responses, in the order their requests
+/// were sent. `None` once responses stop being received.
+struct Pending(Mutex<Option<VecDeque<Waiter>>>);
+
+/// The response to a request sent by a [`Writer`].
+pub(crate) struct Reply(mpsc::Receiver<schema::Response>);
+
+/// The response to a request sent by a [`Writer`], awaited
+/// without blocking.
+#[cfg(feature = "tokio")]
+pub(crate) struct AsyncReply(oneshot::Receiver<schema::Response>);
+
+/// Closes a connection from another thread, e.g. to stop a
+/// reader blocked on it.
+pub(crate) enum Closer {
+    Socket(TcpStream),
+    /// Set to stop a reader polling the connection.
+    #[cfg_attr(not(feature = "serial"), allow(dead_code))]
+    Flag(Arc<AtomicBool>),
+}
+
+impl Transport {
+    /// Request a connection to the server.
+    pub(crate) fn handshake(
+        &mut self,
+        request: ConnectionRequest,
+    ) -> Result<ConnectionResponse, RpcError> {
+        match self {
+            Transport::Tcp(conn) => {
+                write_message(conn, &request)?;
+                read_message_unbuffered(conn)
+            }
+            _ => Err(RpcError::Client),
+        }
+    }
+
+    /// Send a request to the server, before the connection is
+    /// split.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn send(
+        &mut self,
+        request: schema::Request,
+    ) -> Result<(), RpcError> {
+        match self {
+            Transport::Tcp(conn) => write_message(conn, &request),
+            Transport::WebSocket(ws) => send_frame(ws, &request),
+            Transport::Multiplexed(_) => Err(RpcError::Client),
+        }
+    }
+
+    /// Receive the response to a request, before the
+    /// connection is split.
+    #[cfg(feature = "websocket")]
+    pub(crate) fn recv(&mut self) -> Result<schema::Response, RpcError> {
+        match self {
+            Transport::Tcp(conn) => read_message_unbuffered(conn),
+            Transport::WebSocket(ws) => recv_frame(ws),
+            Transport::Multiplexed(_) => Err(RpcError::Client),
+        }
+    }
+
+    /// Fail sending after `timeout`, and wait to receive for
+    /// as long as it takes. Callers wait for responses with
+    /// their own deadlines.
+    pub(crate) fn set_timeout(
+        &mut self,
+        timeout: Option<Duration>,
+    ) -> Result<(), RpcError> {
+        match self {
+            Transport::Tcp(conn)
+            | Transport::Multiplexed(Device::Tcp(conn)) => {
+                set_timeout(conn, timeout)
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ws) => {
+                set_timeout(&ws.get_ref().conn, timeout)
+            }
+            #[cfg(feature = "serial")]
+            Transport::Multiplexed(Device::Io { writer, .. }) => {
+                writer.timeout = timeout;
+                Ok(())
+            }
+        }
+    }
+
+    /// Split an RPC connection into a [`Writer`], and a
+    /// [`Reader`] that passes responses on to it.
+    pub(crate) fn into_rpc(self) -> Result<(Writer, Reader), RpcError> {
+        let multiplexed = matches!(self, Transport::Multiplexed(_));
+        let (read, write, closer) = match self {
+            Transport::Tcp(conn)
+            | Transport::Multiplexed(Device::Tcp(conn)) => (
+                ReadHalf::io(conn.try_clone()?),
+                WriteHalf::Io(Box::new(conn.try_clone()?)),
+                Closer::Socket(conn),
+            ),
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ws) => {
+                use tungstenite::protocol::Role;
+
+                // The reader keeps any data received with the
+                // last response.
+                let write = tungstenite::WebSocket::from_raw_socket(
+                    ws.get_ref().try_clone()?,
+                    Role::Client,
+                    None,
+                );
+                let closer = Closer::Socket(ws.get_ref().conn.try_clone()?);
+                (
+                    ReadHalf::WebSocket(ws),
+                    WriteHalf::WebSocket(Box::new(write)),
+                    closer,
+                )
+            }
+            #[cfg(feature = "serial")]
+            Transport::Multiplexed(Device::Io { mut reader, writer }) => {
+                reader.timeout = None;
+                let closer = Closer::Flag(reader.closed.clone());
+                (
+                    ReadHalf::io(reader),
+                    WriteHalf::Io(Box::new(writer)),
+                    closer,
+                )
+            }
+        };
+        let pending = Arc::new(Pending::new());
+        let (requests, queue) = mpsc::channel();
+        let writer = pending.clone();
+        thread::spawn(move || {
+            write_requests(write, multiplexed, writer, queue)
+        });
+
+        Ok((
+            Writer { requests },
+            Reader {
+                half: read,
+                messages: if multiplexed {
+                    Messages::Multiplexed
+                } else {
+                    Messages::Responses
+                },
+                pending: Some(pending),
+                closer,
+            },
+        ))
+    }
+
+    /// A [`Reader`] of the updates on a stream connection.
+    pub(crate) fn into_updates(self) -> Result<Reader, RpcError> {
+        let (half, closer) = match self {
+            Transport::Tcp(conn) => {
+                let closer = Closer::Socket(conn.try_clone()?);
+                (ReadHalf::io(conn), closer)
+            }
+            #[cfg(feature = "websocket")]
+            Transport::WebSocket(ws) => {
+                let closer = Closer::Socket(ws.get_ref().conn.try_clone()?);
+                (ReadHalf::WebSocket(ws), closer)
+            }
+            Transport::Multiplexed(_) => return Err(RpcError::Client),
+        };
+
+        Ok(Reader {
+            half,
+            messages: Messages::Updates,
+            pending: None,
+            closer,
+        })
+    }
+}
+
+impl Writer {
+    /// Send a request to the server, returning a [`Reply`] to
+    /// wait for its response on. The connection is free for
+    /// other requests while waiting.
+    pub(crate) fn send(
+        &self,
+        request: schema::Request,
+    ) -> Result<Reply, RpcError> {
+        let (sender, receiver) = mpsc::channel();
+        self.queue(request, Waiter::Blocking(sender))?;
+        Ok(Reply(receiver))
+    }
+
+    /// Send a request to the server, returning an
+    /// [`AsyncReply`] to await its response on.
+    #[cfg(feature = "tokio")]
+    pub(crate) fn send_async(
+        &self,
+        request: schema::Request,
+    ) -> Result<AsyncReply, RpcError> {
+        let (sender, receiver) = oneshot::channel();
+        self.queue(request, Waiter::Async(sender))?;
+        Ok(AsyncReply(receiver))
+    }
+
+    fn queue(
+        &self,
+        request: schema::Request,
+        waiter: Waiter,
+    ) -> Result<(), RpcError> {
+        // The writing thread stops once the connection fails.
+        self.requests.send((request, waiter)).map_err(|_| closed())
+    }
+}
+
+impl WriteHalf {
+    fn send(
+        &mut self,
+        request: schema::Request,
+        multiplexed: bool,
+    ) -> Result<(), RpcError> {
+        match self {
+            WriteHalf::Io(writer) if multiplexed => {
+                let request = schema::MultiplexedRequest {
+                    request: Some(request).into(),
+                    ..Default::default()
+                };
+                write_message(writer, &request)
+            }
+            WriteHalf::Io(writer) => write_message(writer, &request),
+            #[cfg(feature = "websocket")]
+            WriteHalf::WebSocket(ws) => send_frame(ws, &request),
+        }
+    }
+}
+
+/// Write the requests queued by a [`Writer`] until it is
+/// dropped, or the connection fails.
+fn write_requests(
+    mut half: WriteHalf,
+    multiplexed: bool,
+    pending: Arc<Pending>,
+    requests: mpsc::Receiver<(schema::Request, Waiter)>,
+) {
+    for (request, waiter) in requests {
+        // Queue the caller first, so the response can't arrive
+        // before it.
+        if pending.push(waiter).is_err() {
+            break;
+        }
+        if half.send(request, multiplexed).is_err() {
+            // Part of the request may have been sent.
+            pending.close();
+            break;
+        }
+    }
+}
+
+impl Reply {
+    /// Wait for the response, failing with
+    /// [`RpcError::Timeout`] after `timeout`. The response is
+    /// discarded if it arrives later.
+    pub(crate) fn wait(
+        self,
+        timeout: Option<Duration>,
+    ) -> Result<schema::Response, RpcError> {
+        match timeout {
+            Some(timeout) => {
+                self.0.recv_timeout(timeout).map_err(|e| match e {
+                    mpsc::RecvTimeoutError::Timeout => RpcError::Timeout,
+                    mpsc::RecvTimeoutError::Disconnected => closed(),
+                })
+            }
+            None => self.0.recv().map_err(|_| closed()),
+        }
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl AsyncReply {
+    /// Wait for the response, failing with
+    /// [`RpcError::Timeout`] after `timeout`. The response is
+    /// discarded if it arrives later.
+    pub(crate) async fn wait(
+        self,
+        timeout: Option<Duration>,
+    ) -> Result<schema::Response, RpcError> {
+        let response = async { self.0.await.map_err(|_| closed()) };
+        match timeout {
+            Some(timeout) => tokio::time::timeout(timeout, response)
+                .await
+                .unwrap_or(Err(RpcError::Timeout)),
+            None => response.await,
+        }
+    }
+}
+
+impl Reader {
+    /// Receive the next stream update, passing on the
+    /// responses received before it.
+    pub(crate) fn recv_update(&mut self) -> Result<StreamUpdate, RpcError> {
+        loop {
+            match self.messages {
+                Messages::Responses => {
+                    let response = self.half.recv()?;
+                    self.complete(response);
+                }
+                Messages::Updates => break self.half.recv(),
+                Messages::Multiplexed => {
+                    let message: schema::MultiplexedResponse =
+                        self.half.recv()?;
+                    if let Some(response) = message.response.into_option() {
+                        self.complete(response);
+                    }
+                    if let Some(update) = message.stream_update.into_option() {
+                        break Ok(update);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn closer(&self) -> Result<Closer, RpcError> {
+        self.closer.try_clone()
+    }
+}
+
+impl ReadHalf {
+    fn io(reader: impl Read + Send + 'static) -> Self {
+        ReadHalf::Io(BufReader::new(Box::new(reader)))
+    }
+
+    fn recv<T: Message>(&mut self) -> Result<T, RpcError> {
+        match self {
+            ReadHalf::Io(reader) => read_message(reader),
+            #[cfg(feature = "websocket")]
+            ReadHalf::WebSocket(ws) => recv_frame(ws),
+        }
+    }
+}
+
+impl Closer {
+    pub(crate) fn close(&self) {
+        match self {
+            Closer::Socket(conn) => {
+                conn.shutdown(Shutdown::Both).ok();
+            }
+            Closer::Flag(closed) => closed.store(true, Ordering::SeqCst),
+        }
+    }
+
+    fn try_clone(&self) -> Result<Closer, RpcError> {
+        match self {
+            Closer::Socket(conn) => Ok(Closer::Socket(conn.try_clone()?)),
+            Closer::Flag(closed) => Ok(Closer::Flag(closed.clone())),
+        }
+    }
+}
+
+impl Reader {
+    fn complete(&self, response: schema::Response) {
+        if let Some(pending) = &self.pending {
+            pending.complete(response);
+        }
+    }
+}
+
+impl Drop for Reader {
+    fn drop(&mut self) {
+        // Nothing else will answer the callers still waiting.
+        if let Some(pending) = &self.pending {
+            pending.close();
+        }
+    }
+}
+
+impl Pending {
+    fn new() -> Self {
+        Self(Mutex::new(Some(VecDeque::new())))
+    }
+
+    fn push(&self, waiter: Waiter) -> Result<(), RpcError> {
+        let mut pending = self.0.lock().unwrap();
+        let waiting = pending.as_mut().ok_or_else(closed)?;
+        waiting.push_back(waiter);
+        Ok(())
+    }
+
+    /// Pass `response` on to the oldest caller waiting.
+    fn complete(&self, response: schema::Response) {
+        let mut pending = self.0.lock().unwrap();
+        if let Some(waiter) = pending.as_mut().and_then(VecDeque::pop_front) {
+            // Nobody is waiting if the call timed out.
+            waiter.send(response);
+        }
+    }
+
+    /// Fail the calls waiting, and any made after.
+    fn close(&self) {
+        self.0.lock().unwrap().take();
+    }
+}
+
+impl Waiter {
+    fn send(self, response: schema::Response) {
+        match self {
+            Waiter::Blocking(sender) => {
+                sender.send(response).ok();
+            }
+            #[cfg(feature = "tokio")]
+            Waiter::Async(sender) => {
+                sender.send(response).ok();
+            }
+        }
+    }
+}
+
+/// Request a connection over a device shared by procedure
+/// calls and stream updates, before it is split with
+/// [`Transport::into_rpc`].
+pub(crate) fn multiplexed_handshake(
+    reader: &mut dyn Read,
+    writer: &mut dyn Write,
+    request: ConnectionRequest,
+) -> Result<ConnectionResponse, RpcError> {
+    let request = schema::MultiplexedRequest {
+        connection_request: Some(request).into(),
+        ..Default::default()
+    };
+    write_message(writer, &request)?;
+    read_message_unbuffered(reader)
+}
+
+/// The longest message accepted from the server.
+const MAX_MESSAGE_LENGTH: usize = 16 * 1024 * 1024;
+
+fn write_message<T: Message>(
+    writer: &mut dyn Write,
+    message: &T,
+) -> Result<(), RpcError> {
+    writer.write_all(&message.write_length_delimited_to_bytes()?)?;
+    writer.flush().map_err(Into::into)
+}
+
+fn read_message<T: Message>(reader: &mut dyn BufRead) -> Result<T, RpcError> {
+    let mut length = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *reader
+            .fill_buf()?
+            .first()
+            .ok_or(std::io::Error::from(std::io::ErrorKind::UnexpectedEof))?;
+        reader.consume(1);
+        length |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+        if shift >= 64 {
+            return Err(RpcError::Encoding("malformed message length".into()));
+        }
+    }
+
+    // Noise on the line, or a peer that isn't a kRPC server,
+    // can claim any length.
+    let length = usize::try_from(length)
+        .ok()
+        .filter(|&length| length <= MAX_MESSAGE_LENGTH)
+        .ok_or_else(|| {
+            RpcError::Encoding(format!("message length {length} too large"))
+        })?;
+    let mut buffer = vec![0; length];
+    reader.read_exact(&mut buffer)?;
+
+    T::parse_from_bytes(&buffer).map_err(Into::into)
+}
+
+/// Read a message from a connection that isn't read from by
+/// a [`Reader`] yet, e.g. during the handshake.
+fn read_message_unbuffered<T: Message>(
+    reader: &mut dyn Read,
+) -> Result<T, RpcError> {
+    // A buffer of one byte never reads ahead, so none of the
+    // next message is consumed before the connection is split.
+    read_message(&mut BufReader::with_capacity(1, reader))
+}
+
+#[cfg(feature = "websocket")]
+fn send_frame<T: Message>(
+    ws: &mut tungstenite::WebSocket<SharedSocket>,
+    message: &T,
+) -> Result<(), RpcError> {
+    let message = message.write_to_bytes()?;
+    ws.send(tungstenite::Message::binary(message))
+        .map_err(Into::into)
+}
+
+#[cfg(feature = "websocket")]
+fn recv_frame<T: Message>(
+    ws: &mut tungstenite::WebSocket<SharedSocket>,
+) -> Result<T, RpcError> {
+    loop {
+        // Pings are answered by the WebSocket itself.
+        match ws.read()? {
+            tungstenite::Message::Binary(message) => {
+                break T::parse_from_bytes(&message).map_err(Into::into);
+            }
+            tungstenite::Message::Close(_) => break Err(closed()),
+            _ => {}
+        }
+    }
+}
+
+fn set_timeout(
+    conn: &TcpStream,
+    timeout: Option<Duration>,
+) -> Result<(), RpcError> {
+    conn.set_read_timeout(None)?;
+    conn.set_write_timeout(timeout).map_err(Into::into)
+}
+
+/// The error returned when the server closes the connection.
+fn closed() -> RpcError {
+    std::io::Error::from(std::io::ErrorKind::ConnectionAborted).into()
+}
+
+#[cfg(feature = "websocket")]
+impl SharedSocket {
+    pub(crate) fn new(conn: TcpStream) -> Self {
+        SharedSocket {
+            conn,
+            buffer: Vec::new(),
+            writing: Arc::new(Mutex::new(())),
+        }
+    }
+
+    fn try_clone(&self) -> Result<SharedSocket, RpcError> {
+        Ok(SharedSocket {
+            conn: self.conn.try_clone()?,
+            buffer: Vec::new(),
+            writing: self.writing.clone(),
+        })
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Read for SharedSocket {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.conn.read(buf)
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl Write for SharedSocket {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.buffer.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() {
+            let _writing = self.writing.lock().unwrap();
+            // Part of a frame may have been written on failure,
+            // which leaves the connection unusable anyway.
+            let result = self.conn.write_all(&self.buffer);
+            self.buffer.clear();
+            result?;
+        }
+        self.conn.flush()
+    }
+}
+
+/// Escape `value` for use in the query of a WebSocket URL.
+#[cfg(feature = "websocket")]
+pub(crate) fn query_escape(value: &[u8]) -> String {
+    value
+        .iter()
+        .map(|&byte| match byte {
+            b'A'..=b'Z'
+            | b'a'..=b'z'
+            | b'0'..=b'9'
+            | b'-'
+            | b'.'
+            | b'_'
+            | b'~' => char::from(byte).to_string(),
+            _ => format!("%{byte:02X}"),
+        })
+        .collect()
+}
+
+/// Half of a device that fails with [`std::io::ErrorKind::TimedOut`]
+/// when idle, polled so that a reader blocked on it can be
+/// stopped with a [`Closer::Flag`].
+#[cfg(feature = "serial")]
+pub(crate) struct Polled<T> {
+    inner: T,
+    closed: Arc<AtomicBool>,
+    /// Give up after this long.
+    pub(crate) timeout: Option<Duration>,
+}
+
+#[cfg(feature = "serial")]
+impl<T> Polled<T> {
+    pub(crate) fn new(
+        inner: T,
+        closed: Arc<AtomicBool>,
+        timeout: Option<Duration>,
+    ) -> Self {
+        Polled {
+            inner,
+            closed,
+            timeout,
+        }
+    }
+
+    /// Retry `op` until it completes or the timeout elapses,
+    /// unless the device is closed.
+    fn poll<U>(
+        &mut self,
+        mut op: impl FnMut(&mut T) -> std::io::Result<U>,
+    ) -> std::io::Result<U> {
+        let start = std::time::Instant::now();
+        loop {
+            if self.closed.load(Ordering::SeqCst) {
+                return Err(std::io::ErrorKind::ConnectionAborted.into());
+            }
+            match op(&mut self.inner) {
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        std::io::ErrorKind::TimedOut
+                            | std::io::ErrorKind::WouldBlock
+                    ) && self
+                        .timeout
+                        .is_none_or(|timeout| start.elapsed() < timeout) => {}
+                result => return result,
+            }
+        }
+    }
+}
+
+#[cfg(feature = "serial")]
+impl<T: Read> Read for Polled<T> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.poll(|inner| inner.read(buf))
+    }
+}
+
+#[cfg(feature = "serial")]
+impl<T: Write> Write for Polled<T> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.poll(|inner| inner.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.poll(|inner| inner.flush())
+    }
+}